@@ -0,0 +1,208 @@
+#[macro_use]
+extern crate criterion;
+extern crate ndarray;
+extern crate rustdca;
+extern crate structopt;
+
+use criterion::{black_box, BatchSize, Criterion, Throughput};
+use ndarray::Axis;
+use rustdca::agent::{simulate_events, Agent, State};
+use rustdca::environment::Env;
+use rustdca::eventgen::{EType, Event, EventGen};
+use rustdca::gridfuncs::*;
+use rustdca::random;
+use rustdca::tabular::Tabular;
+use rustdca::vnet_agent::{AAVNet, Net, VNet};
+use rustdca::Opt;
+use std::sync::atomic::AtomicBool;
+use structopt::StructOpt;
+
+const SEED: u64 = 0;
+// Number of events simulated in each iteration of the 'simulate' benchmarks
+const N_EVENTS: u64 = 2000;
+
+/// The grid after simulating a few thousand events with a policy that assigns the
+/// lowest eligible channel, so that the grid has a realistic channel usage
+fn busy_grid() -> BitGrid {
+    random::seed(SEED);
+    let (mut env, mut event) = Env::new(&Opt::from_iter(&["DCA"]));
+    for _ in 0..5000 {
+        let action = match event.etype {
+            EType::END => event.ch,
+            _ => get_eligible_chs(&env.grid, &event.cell).first().cloned(),
+        };
+        event = env.step(event, action).unwrap().1;
+    }
+    env.grid
+}
+
+/// The cell with the most channels in use on 'grid'
+fn busiest_cell(grid: &BitGrid) -> Cell {
+    let (row, col) = (0..ROWS)
+        .flat_map(|r| (0..COLS).map(move |c| (r, c)))
+        .max_by_key(|&(r, c)| grid.chs(r, c).count_ones())
+        .unwrap();
+    Cell { row, col }
+}
+
+/// An event of type 'etype' in 'cell', handing off to a neighbor if 'to_cell' is given
+fn event(etype: EType, cell: Cell, ch: Option<usize>, to_cell: Option<Cell>) -> Event {
+    Event {
+        id: 0,
+        time: 0.0,
+        etype,
+        cell,
+        ch,
+        to_cell,
+    }
+}
+
+fn bench_gridfuncs(c: &mut Criterion) {
+    let grid = busy_grid();
+    let frep = feature_rep(&grid);
+    let cell = busiest_cell(&grid);
+    let eligible = get_eligible_chs(&grid, &cell);
+    let inuse = get_inuse_chs(&grid, &cell);
+
+    c.bench_function("feature_rep", |b| b.iter(|| feature_rep(black_box(&grid))));
+    c.bench_function("get_eligible_chs", |b| {
+        b.iter(|| get_eligible_chs(black_box(&grid), black_box(&cell)))
+    });
+    c.bench_function("incremental_freps NEW", |b| {
+        b.iter(|| incremental_freps(black_box(&grid), &frep, &cell, &EType::NEW, &eligible))
+    });
+    c.bench_function("incremental_freps END", |b| {
+        b.iter(|| incremental_freps(black_box(&grid), &frep, &cell, &EType::END, &inuse))
+    });
+}
+
+/// Handling of events by the event generator alone, with a call on the first channel
+/// of the cell for each new call, for a queue with a realistic number of pending events
+fn bench_eventgen(c: &mut Criterion) {
+    let opt = Opt::from_iter(&["DCA"]);
+    random::seed(SEED);
+    let mut eventgen = EventGen::new(&opt);
+    let mut grid = BitGrid::default();
+    for r in 0..ROWS {
+        for c in 0..COLS {
+            eventgen.event_new(0.0, Cell { row: r, col: c })
+        }
+    }
+    let mut step = move || {
+        let event = eventgen.pop().unwrap();
+        let (r, c) = (event.cell.row, event.cell.col);
+        match event.etype {
+            EType::END => grid.set(r, c, event.ch.unwrap(), false),
+            _ => {
+                eventgen.event_new(event.time, event.cell.clone());
+                if let Some(ch) = get_eligible_chs(&grid, &event.cell).first() {
+                    grid.set(r, c, *ch, true);
+                    eventgen.event_end(event.time, event.cell, *ch);
+                }
+            }
+        }
+    };
+    for _ in 0..10000 {
+        step();
+    }
+    c.bench_function("EventGen pop and push", |b| b.iter(&mut step));
+}
+
+fn bench_vnet(c: &mut Criterion) {
+    let opt = Opt::from_iter(&["DCA"]);
+    let mut net = VNet::new(&opt);
+    let grid = busy_grid();
+    let frep = feature_rep(&grid);
+    let cell = busiest_cell(&grid);
+    let eligible = get_eligible_chs(&grid, &cell);
+    let freps = incremental_freps(&grid, &frep, &cell, &EType::NEW, &eligible);
+    let next_frep = freps.subview(Axis(0), 0).to_owned();
+
+    c.bench_function("VNet forward", |b| {
+        b.iter(|| net.forward(black_box(&freps)))
+    });
+    c.bench_function("VNet backward", |b| {
+        b.iter(|| net.backward(black_box(&frep), 1.0, 0.1, 1.0, &next_frep))
+    });
+}
+
+fn bench_get_action(c: &mut Criterion) {
+    let opt = Opt::from_iter(&["DCA"]);
+    let mut agent = AAVNet::<VNet>::new(&opt);
+    let grid = busy_grid();
+    let frep = feature_rep(&grid);
+    let cell = busiest_cell(&grid);
+    let to_cell = cell_of(neighbors(1, cell.row, cell.col, false), 0);
+    let ch = get_inuse_chs(&grid, &cell)[0];
+    let mut states = vec![
+        (
+            "AAVNet get_action",
+            event(EType::END, cell.clone(), Some(ch), None),
+        ),
+        (
+            "AAVNet get_action HLA",
+            event(EType::END, cell.clone(), Some(ch), Some(to_cell)),
+        ),
+    ];
+    for (name, event) in states.drain(..) {
+        let mut state = State {
+            grid,
+            frep: frep.clone(),
+            event,
+            dt: 0.0,
+        };
+        c.bench_function(name, |b| b.iter(|| agent.get_action(black_box(&mut state))));
+    }
+}
+
+/// Simulation throughput in events per second, from a fixed seed
+fn bench_simulate(c: &mut Criterion) {
+    fn simulate<A: Agent>(opt: &Opt) {
+        let running = AtomicBool::new(true);
+        let (mut env, event) = Env::new(opt);
+        let mut agent = A::new(opt);
+        simulate_events(opt, &mut env, &mut agent, event, &running).unwrap();
+    }
+
+    let n_events = N_EVENTS.to_string();
+    let args = ["DCA", "-i", &n_events, "--log_iter", "1000000000"];
+    let opt_vnet = Opt::from_iter(&args);
+    let opt_hla = Opt::from_iter(args.iter().chain(&["-p", "0.15"]));
+    let opt_tabular = Opt::from_iter(args.iter().chain(&["--agent", "QLearning"]));
+
+    let mut group = c.benchmark_group("simulate");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(N_EVENTS));
+    group.bench_function("AAVNet", |b| {
+        b.iter_batched(
+            || random::seed(SEED),
+            |_| simulate::<AAVNet<VNet>>(&opt_vnet),
+            BatchSize::PerIteration,
+        )
+    });
+    group.bench_function("AAVNet hand-offs", |b| {
+        b.iter_batched(
+            || random::seed(SEED),
+            |_| simulate::<AAVNet<VNet>>(&opt_hla),
+            BatchSize::PerIteration,
+        )
+    });
+    group.bench_function("QLearning", |b| {
+        b.iter_batched(
+            || random::seed(SEED),
+            |_| simulate::<Tabular>(&opt_tabular),
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_gridfuncs,
+    bench_eventgen,
+    bench_vnet,
+    bench_get_action,
+    bench_simulate
+);
+criterion_main!(benches);
@@ -1,23 +1,131 @@
 use super::Opt;
 use ctrlc::set_handler;
 use environment::Env;
-use eventgen::Event;
-use gridfuncs::{feature_rep, n_used, FrepO, GridO};
+use error::EnvError;
+use eventgen::{EType, Event};
+use gridfuncs::{get_eligible_chs, get_inuse_chs, n_used, BitGrid, FrepO};
+use random;
+use std::io;
+use std::path::Path;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
 pub struct State {
-    pub grid: GridO,
+    pub grid: BitGrid,
     pub frep: FrepO,
     pub event: Event,
+    pub dt: f64, // Time since the previous event, in minutes
 }
 
 pub type Action = Option<usize>;
 
+arg_enum! {
+    /// Agents:
+    /// - AAVNet: Afterstate value network ('--net')
+    /// - QLearning: Tabular Q-learning
+    /// - SARSA: Tabular SARSA
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum AgentKind {
+        AAVNet,
+        QLearning,
+        SARSA
+    }
+}
+
+/// Optimality criterion of an agent
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Criterion {
+    /// Average reward per event
+    AvgReward,
+    /// Average reward per minute, for rewards integrated over the time between events
+    ContinuousAvgReward,
+    /// Discounted return, with the value of the next state discounted by 'gamma' per event,
+    /// or per minute between events if 'semi_markov'
+    Discounted { gamma: f32, semi_markov: bool },
+}
+
+impl Criterion {
+    pub fn new(opt: &Opt) -> Self {
+        match opt.gamma {
+            Some(gamma) => Criterion::Discounted {
+                gamma,
+                semi_markov: opt.semi_markov,
+            },
+            None if opt.continuous_time => Criterion::ContinuousAvgReward,
+            None => Criterion::AvgReward,
+        }
+    }
+
+    pub fn is_avg_reward(&self) -> bool {
+        match *self {
+            Criterion::AvgReward | Criterion::ContinuousAvgReward => true,
+            Criterion::Discounted { .. } => false,
+        }
+    }
+
+    /// The average reward over a transition lasting 'dt' minutes, given the estimated
+    /// average reward 'avg_reward'. It is subtracted from the reward in the TD error,
+    /// and is zero for the discounted return criterion.
+    pub fn avg_reward(&self, avg_reward: f32, dt: f64) -> f32 {
+        match *self {
+            Criterion::AvgReward => avg_reward,
+            Criterion::ContinuousAvgReward => avg_reward * dt as f32,
+            Criterion::Discounted { .. } => 0.0,
+        }
+    }
+
+    /// Discount of the value of the next state for a transition lasting 'dt' minutes
+    pub fn discount(&self, dt: f64) -> f32 {
+        match *self {
+            Criterion::AvgReward | Criterion::ContinuousAvgReward => 1.0,
+            Criterion::Discounted {
+                gamma,
+                semi_markov: false,
+            } => gamma,
+            Criterion::Discounted {
+                gamma,
+                semi_markov: true,
+            } => gamma.powf(dt as f32),
+        }
+    }
+}
+
+/// The channels that can be assigned to the event of 'state', if it is an arrival,
+/// or that can be released or reassigned, if it is an end event
+pub fn available_chs(state: &State) -> Vec<usize> {
+    match state.event.etype {
+        EType::END => get_inuse_chs(&state.grid, &state.event.cell),
+        _ => get_eligible_chs(&state.grid, &state.event.cell),
+    }
+}
+
 pub trait Agent {
-    fn new(alpha: f32, alpha_avg: f32, alpha_grad: f32) -> Self;
+    fn new(opt: &Opt) -> Self;
     fn get_action(&mut self, state: &mut State) -> (Action, FrepO);
-    fn update(&mut self, state: &State, action: Action, reward: i32, next_state: &State);
+    fn update(&mut self, state: &State, action: Action, reward: f32, next_state: &State);
+    /// Select an action for each state of a batch, as from parallel environments
+    fn get_actions(&mut self, states: &mut [State]) -> Vec<(Action, FrepO)> {
+        states
+            .iter_mut()
+            .map(|state| self.get_action(state))
+            .collect()
+    }
+    /// Update on a batch of transitions, as from parallel environments
+    fn update_batch(
+        &mut self,
+        states: &[State],
+        actions: &[Action],
+        rewards: &[f32],
+        next_states: &[State],
+    ) {
+        for (state, &action, &reward, next_state) in izip!(states, actions, rewards, next_states) {
+            self.update(state, action, reward, next_state);
+        }
+    }
+    /// Load the weights of the agent's value function from a '.npy' file
+    fn load_weights(&mut self, path: &Path) -> io::Result<()>;
+    /// Save the weights of the agent's value function to a '.npy' file
+    fn save_weights(&self, path: &Path) -> io::Result<()>;
 }
 
 /// (x_t, e_t) -> a_t -> r_{t+1} -> (x_{t+1}, e_{t+1})
@@ -27,16 +135,51 @@ pub fn simulate<A: Agent>(opt: &Opt) {
     let r = running.clone();
     set_handler(move || {
         r.store(false, Ordering::SeqCst);
-    }).expect("Error setting Ctrl-C handler");
+    })
+    .expect("Error setting Ctrl-C handler");
 
-    // Initialize the agent and the environment; get the first
-    // call event to handle and the first action response to that event
+    if let Some(seed) = opt.seed {
+        random::seed(seed);
+    }
+    // Initialize the agent and the environment, and get the first call event to handle
     let (mut env, event) = Env::new(opt);
-    let mut agent: A = A::new(opt.alpha, opt.alpha_avg, opt.alpha_grad);
+    let mut agent: A = A::new(opt);
+    if let Some(ref path) = opt.load_weights {
+        agent
+            .load_weights(Path::new(path))
+            .expect("Failed to load weights");
+    }
+    // An error ends the simulation early, but the weights are saved nonetheless
+    match simulate_events(opt, &mut env, &mut agent, event, &running) {
+        Ok(t_end) => {
+            if let Err(err) = env.stats.report_end(t_end, n_used(&env.grid)) {
+                println!("\n{}", err);
+            }
+        }
+        Err(err) => println!("\nSimulation stopped: {}", err),
+    }
+    if let Some(ref path) = opt.save_weights {
+        agent
+            .save_weights(Path::new(path))
+            .expect("Failed to save weights");
+    }
+}
+
+/// Simulate 'opt.n_events' events on 'env' with 'agent', starting with 'event',
+/// or until 'running' is cleared. Return the time of the last event.
+pub fn simulate_events<A: Agent>(
+    opt: &Opt,
+    env: &mut Env,
+    agent: &mut A,
+    event: Event,
+    running: &AtomicBool,
+) -> Result<f64, EnvError> {
+    // Get the first action response to the first event
     let mut state = State {
-        grid: env.grid.clone(),
-        frep: feature_rep(&env.grid),
-        event: event,
+        grid: env.grid,
+        frep: env.frep.clone(),
+        dt: event.time,
+        event,
     };
     let (mut action, mut next_frep) = agent.get_action(&mut state);
     let mut next_state;
@@ -45,13 +188,21 @@ pub fn simulate<A: Agent>(opt: &Opt) {
             println!("Premature exit");
             break;
         }
-        let (reward, next_event) = env.step(state.event.clone(), action);
+        let (reward, next_event) = env.step(state.event.clone(), action)?;
+        // Neither a repacking move by the environment nor a substituted action is part
+        // of the agent's afterstate
+        let afterstate = env.repacked.is_none() && env.substituted.is_none();
+        if opt.verify_frep && afterstate && next_frep != env.frep {
+            return Err(EnvError::FrepMismatch);
+        }
+        let executed = env.substituted.unwrap_or(action);
         next_state = State {
-            grid: env.grid.clone(),
-            frep: next_frep,
+            grid: env.grid,
+            frep: env.frep.clone(),
+            dt: next_event.time - state.event.time,
             event: next_event,
         };
-        agent.update(&state, action, reward as i32, &next_state);
+        agent.update(&state, executed, reward, &next_state);
         let (a, f) = agent.get_action(&mut next_state);
         action = a;
         next_frep = f;
@@ -61,5 +212,81 @@ pub fn simulate<A: Agent>(opt: &Opt) {
             env.stats.report_log_iter(i);
         }
     }
-    env.stats.report_end(state.event.time, n_used(&env.grid));
+    Ok(state.event.time)
+}
+
+#[cfg(test)]
+mod tests {
+    use agent::*;
+    use gridfuncs::incremental_freps;
+    use ndarray::Axis;
+    use structopt::StructOpt;
+
+    /// Assigns channel 0 to every arrival, whether or not it is eligible
+    struct FirstChannel {
+        n_substituted: usize,
+    }
+
+    impl Agent for FirstChannel {
+        fn new(_opt: &Opt) -> Self {
+            FirstChannel { n_substituted: 0 }
+        }
+
+        fn get_action(&mut self, state: &mut State) -> (Action, FrepO) {
+            let (etype, cell) = (&state.event.etype, &state.event.cell);
+            let ch = match *etype {
+                EType::END => state.event.ch.unwrap(),
+                _ => 0,
+            };
+            let legal = *etype == EType::END || get_eligible_chs(&state.grid, cell).contains(&ch);
+            let frep = if legal {
+                incremental_freps(&state.grid, &state.frep, cell, etype, &[ch])
+                    .subview(Axis(0), 0)
+                    .to_owned()
+            } else {
+                state.frep.clone()
+            };
+            (Some(ch), frep)
+        }
+
+        fn update(&mut self, state: &State, action: Action, _reward: f32, _next_state: &State) {
+            if state.event.etype == EType::END {
+                assert_eq!(action, state.event.ch);
+            } else if action != Some(0) {
+                if let Some(ch) = action {
+                    assert!(get_eligible_chs(&state.grid, &state.event.cell).contains(&ch));
+                }
+                self.n_substituted += 1;
+            }
+        }
+
+        fn load_weights(&mut self, _path: &Path) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn save_weights(&self, _path: &Path) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    /// Agents are updated on the fallback actions executed in place of illegal actions,
+    /// whose afterstates are not verified against those of the illegal actions
+    fn test_fallback_update() {
+        let opt = Opt::from_iter(&[
+            "DCA",
+            "--illegal_action",
+            "Fallback",
+            "--verify_frep",
+            "-i",
+            "1000",
+        ]);
+        random::seed(0);
+        let (mut env, event) = Env::new(&opt);
+        let mut agent = FirstChannel::new(&opt);
+        let running = AtomicBool::new(true);
+        simulate_events(&opt, &mut env, &mut agent, event, &running).unwrap();
+        assert!(agent.n_substituted > 0);
+        assert_eq!(agent.n_substituted, env.stats.n_illegal_actions() as usize);
+    }
 }
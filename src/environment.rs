@@ -1,44 +1,93 @@
 use super::Opt;
 use agent::Action;
+use error::EnvError;
 use eventgen::*;
 use gridfuncs::*;
-use ndarray::{Array, Array3};
-use rand::{thread_rng, Rng};
+use rand::Rng;
+use random::rng;
+use reward::RewardFn;
 use stats::Stats;
 
+arg_enum! {
+    /// Responses to an action that is not legal for the event, i.e. a channel that is not
+    /// eligible in the cell of an arrival, or not in use in the cell of an end event:
+    /// - Error: Return an error from 'Env::step', leaving the environment unchanged
+    /// - Panic: Panic
+    /// - Block: Block the call arrival. An end event frees the channel of the ending call.
+    /// - Fallback: Assign the lowest eligible channel to the call arrival, or block it if
+    ///   there is none. An end event frees the channel of the ending call.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum IllegalActionPolicy {
+        Error,
+        Panic,
+        Block,
+        Fallback
+    }
+}
+
 pub struct Env {
     p_handoff: f32,
     verify_grid: bool,
-    pub grid: GridO,
+    verify_frep: bool,
+    arrival_repack: bool,
+    illegal_action: IllegalActionPolicy,
+    reward_fn: RewardFn,
+    pub grid: BitGrid,
+    // Feature representation of the grid, updated with each executed action
+    pub frep: FrepO,
     pub stats: Stats,
     eventgen: EventGen,
+    // The channels an ongoing call was moved from and to by the repacking move of the
+    // last step, if any
+    pub repacked: Option<(usize, usize)>,
+    // The action executed in place of an illegal action by the last step, if any
+    pub substituted: Option<Action>,
 }
 
 impl Env {
     /// Initialize an environment and return the first event to be processed
     pub fn new(opt: &Opt) -> (Env, Event) {
-        let grid: Array3<bool> = Array::default((ROWS, COLS, CHANNELS));
+        let grid = BitGrid::default();
         let mut eventgen = EventGen::new(opt);
         for r in 0..ROWS {
             for c in 0..COLS {
                 eventgen.event_new(0.0, Cell { row: r, col: c })
             }
         }
-        let event = eventgen.pop();
+        let event = eventgen
+            .pop()
+            .expect("An event was generated for each cell");
         (
             Env {
                 p_handoff: opt.p_hoff,
                 verify_grid: opt.verify_grid,
-                grid: grid,
+                verify_frep: opt.verify_frep,
+                arrival_repack: opt.arrival_repack,
+                illegal_action: opt.illegal_action,
+                reward_fn: RewardFn::new(opt),
+                frep: feature_rep(&grid),
+                grid,
                 stats: Stats::new(),
-                eventgen: eventgen,
+                eventgen,
+                repacked: None,
+                substituted: None,
             },
             event,
         )
     }
 
-    pub fn step(&mut self, event: Event, action: Action) -> (usize, Event) {
-        let (time, cell) = (event.time, event.cell.clone());
+    /// Execute 'action' on 'event' and return the reward and the next event.
+    /// An illegal action is handled according to the illegal action policy
+    /// before the environment is changed.
+    pub fn step(&mut self, event: Event, action: Action) -> Result<(f32, Event), EnvError> {
+        let executed = self.validate_action(&event, action)?;
+        self.substituted = if executed != action {
+            Some(executed)
+        } else {
+            None
+        };
+        let action = executed;
+        let (time, cell, etype) = (event.time, event.cell.clone(), event.etype.clone());
         debug!("Time: {}, etype: {}, ch: {:?}", time, event.etype, action);
         match event.etype {
             EType::NEW => {
@@ -47,7 +96,7 @@ impl Env {
                 match action {
                     Some(ch) => {
                         self.stats.event_accept_new();
-                        let p = thread_rng().gen::<f32>();
+                        let p = rng().gen::<f32>();
                         if p < self.p_handoff {
                             self.eventgen.event_hoff_new(time, cell, ch);
                         } else {
@@ -72,39 +121,280 @@ impl Env {
             }
             EType::END => {
                 self.stats.event_end();
-                assert!(action.is_some())
             }
         }
-        action.map(|ch| self.execute_action(event, ch));
+        self.repacked = None;
+        if let Some(ch) = action {
+            let cell = event.cell.clone();
+            self.execute_action(event, ch)?;
+            if self.arrival_repack && etype != EType::END {
+                self.repacked = self.repack(&cell, ch)?;
+            }
+        }
         if self.verify_grid {
-            assert!(validate_reuse_constraint(&self.grid).is_ok());
+            validate_reuse_constraint(&self.grid).map_err(EnvError::ReuseConstraint)?;
         }
-        let reward = n_used(&self.grid);
+        if self.verify_frep && self.frep != feature_rep(&self.grid) {
+            return Err(EnvError::FrepMismatch);
+        }
+        let next_event = self.eventgen.pop()?;
+        let reward = self
+            .reward_fn
+            .reward(&self.grid, &etype, action, next_event.time - time);
         debug!("Reward: {}", reward);
-        (reward, self.eventgen.pop())
+        Ok((reward, next_event))
+    }
+
+    /// Validate 'action' against the eligible channels in the cell of an arrival, or the
+    /// channels in use in the cell of an end event, and return the action to execute
+    /// according to the illegal action policy
+    fn validate_action(&mut self, event: &Event, action: Action) -> Result<Action, EnvError> {
+        let err = match self.check_action(event, action) {
+            Ok(()) => return Ok(action),
+            Err(err) => err,
+        };
+        let substitute = match (self.illegal_action, &event.etype) {
+            (IllegalActionPolicy::Error, _) => return Err(err),
+            (IllegalActionPolicy::Panic, _) => panic!("Illegal action: {}", err),
+            (_, &EType::END) => event.ch,
+            (IllegalActionPolicy::Block, _) => None,
+            (IllegalActionPolicy::Fallback, _) => {
+                get_eligible_chs(&self.grid, &event.cell).first().cloned()
+            }
+        };
+        debug!("Illegal action: {}. Substituted {:?}", err, substitute);
+        // An end event without the channel of the call cannot be handled
+        self.check_action(event, substitute)?;
+        self.stats.event_illegal_action();
+        Ok(substitute)
+    }
+
+    /// Check that 'action' can be executed on 'event': An arrival must be assigned a channel
+    /// that is eligible in its cell, if any, and an end event must free a channel in use in
+    /// its cell. When the freed channel differs from that of the ending call, the call on the
+    /// freed channel is reassigned to the channel of the ending call, which requires a pending
+    /// end event for it.
+    fn check_action(&self, event: &Event, action: Action) -> Result<(), EnvError> {
+        let (r, c) = (event.cell.row, event.cell.col);
+        if let Some(ch) = action {
+            if ch >= CHANNELS {
+                return Err(EnvError::InvalidChannel(ch));
+            }
+        }
+        match (&event.etype, action) {
+            (&EType::END, None) => Err(EnvError::MissingAction(event.clone())),
+            (&EType::END, Some(ch)) => {
+                let end_ch = event
+                    .ch
+                    .ok_or_else(|| EnvError::MissingEndChannel(event.clone()))?;
+                for &inuse_ch in &[end_ch, ch] {
+                    if !self.grid.get(r, c, inuse_ch) {
+                        return Err(EnvError::ChannelNotInUse(event.cell.clone(), inuse_ch));
+                    }
+                }
+                if ch != end_ch && self.eventgen.end_event(&event.cell, ch).is_none() {
+                    return Err(EnvError::MissingEndEvent(event.cell.clone(), ch));
+                }
+                Ok(())
+            }
+            (_, Some(ch)) if self.grid.get(r, c, ch) => {
+                Err(EnvError::ChannelInUse(event.cell.clone(), ch))
+            }
+            (_, Some(ch)) if !get_eligible_chs(&self.grid, &event.cell).contains(&ch) => {
+                Err(EnvError::IneligibleChannel(event.cell.clone(), ch))
+            }
+            (_, _) => Ok(()),
+        }
     }
 
-    pub fn execute_action(&mut self, event: Event, ch: usize) {
+    pub fn execute_action(&mut self, event: Event, ch: usize) -> Result<(), EnvError> {
         debug!("Executing action {:?}, {}", event, ch);
+        self.check_action(&event, Some(ch))?;
         let (r, c) = (event.cell.row, event.cell.col);
-        let dbgstr = format!(
-            "Unexpected grid state when executing action {:?}, CH: {}",
-            event, ch
-        );
         match event.etype {
             EType::END => {
-                let reass_ch = event.ch.expect("No CH for end event");
-                assert!(self.grid[[r, c, reass_ch]], dbgstr);
+                let reass_ch = event
+                    .ch
+                    .ok_or_else(|| EnvError::MissingEndChannel(event.clone()))?;
                 if reass_ch != ch {
-                    assert!(self.grid[[r, c, ch]], dbgstr);
-                    self.eventgen.reassign(event.cell, ch, reass_ch);
+                    self.eventgen.reassign(event.cell.clone(), ch, reass_ch)?;
+                    self.stats.event_reassign();
                 }
-                self.grid[[r, c, ch]] = false;
+                self.grid.set(r, c, ch, false);
             }
             _ => {
-                assert!(!self.grid[[r, c, ch]], dbgstr);
-                self.grid[[r, c, ch]] = true;
+                self.grid.set(r, c, ch, true);
+            }
+        }
+        update_frep(&self.grid, &mut self.frep, &event.cell, ch);
+        Ok(())
+    }
+
+    /// Repacking move following the assignment of channel 'ch' to a call arriving in 'cell':
+    /// Move the ongoing call in 'cell', other than the arriving one, whose channel is in use
+    /// in the fewest cells within a distance of 4, to the eligible channel that is in use in
+    /// the most, if that is more. Channels that are reused at the shortest distance allowed
+    /// are left free in more cells nearby. Return the channels moved from and to, if any.
+    fn repack(&mut self, cell: &Cell, ch: usize) -> Result<Option<(usize, usize)>, EnvError> {
+        let (r, c) = (cell.row, cell.col);
+        // The number of cells within a distance of 4, not including 'cell', that use a channel
+        let n_used = |frep: &FrepO, ch: usize| frep[[r, c, ch]];
+        let from_ch = get_inuse_chs(&self.grid, cell)
+            .into_iter()
+            .filter(|&inuse_ch| inuse_ch != ch)
+            .min_by_key(|&inuse_ch| n_used(&self.frep, inuse_ch));
+        let to_ch = get_eligible_chs(&self.grid, cell)
+            .into_iter()
+            .max_by_key(|&elig_ch| n_used(&self.frep, elig_ch));
+        let (from_ch, to_ch) = match (from_ch, to_ch) {
+            (Some(from_ch), Some(to_ch))
+                if n_used(&self.frep, to_ch) > n_used(&self.frep, from_ch) =>
+            {
+                (from_ch, to_ch)
             }
+            _ => return Ok(None),
+        };
+        debug!("Repacking call in {:?} from {} to {}", cell, from_ch, to_ch);
+        self.eventgen.reassign(cell.clone(), from_ch, to_ch)?;
+        self.grid.set(r, c, from_ch, false);
+        update_frep(&self.grid, &mut self.frep, cell, from_ch);
+        self.grid.set(r, c, to_ch, true);
+        update_frep(&self.grid, &mut self.frep, cell, to_ch);
+        self.stats.event_reassign();
+        Ok(Some((from_ch, to_ch)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use environment::*;
+    use error::EnvError;
+    use structopt::StructOpt;
+
+    #[test]
+    /// A call arrival moves an ongoing call in the cell to a channel that is in use nearby,
+    /// along with its end event
+    fn test_arrival_repack() {
+        let (mut env, _) = Env::new(&Opt::from_iter(&["DCA", "--arrival_repack"]));
+        let cell = Cell { row: 3, col: 3 };
+        // A cell outside the reuse distance of 'cell', but within a distance of 4
+        let neighs2 = neighbors(2, cell.row, cell.col, true);
+        let far = neighbors(4, cell.row, cell.col, false)
+            .outer_iter()
+            .map(|neigh| Cell {
+                row: neigh[0],
+                col: neigh[1],
+            })
+            .find(|neigh| {
+                !neighs2
+                    .outer_iter()
+                    .any(|n| n[0] == neigh.row && n[1] == neigh.col)
+            })
+            .unwrap();
+        for &(ref cell, ch) in &[(cell.clone(), 0), (far.clone(), 5)] {
+            env.grid.set(cell.row, cell.col, ch, true);
+            env.eventgen.event_end(0.0, cell.clone(), ch);
+        }
+        env.frep = feature_rep(&env.grid);
+        let event = Event {
+            id: 0,
+            time: 0.0,
+            etype: EType::NEW,
+            cell: cell.clone(),
+            ch: None,
+            to_cell: None,
+        };
+        env.step(event, Some(10)).unwrap();
+        assert_eq!(env.repacked, Some((0, 5)));
+        assert_eq!(get_inuse_chs(&env.grid, &cell), vec![5, 10]);
+        assert_eq!(env.frep, feature_rep(&env.grid));
+        assert!(env.eventgen.end_event(&cell, 0).is_none());
+        assert!(env.eventgen.end_event(&cell, 5).is_some());
+        assert_eq!(env.stats.n_reassigned(), 1);
+    }
+
+    #[test]
+    /// Invalid actions are rejected and leave the environment unchanged
+    fn test_invalid_actions() {
+        let (mut env, event) = Env::new(&Opt::from_iter(&["DCA"]));
+        let cell = event.cell.clone();
+        match env.step(event.clone(), Some(CHANNELS)) {
+            Err(EnvError::InvalidChannel(ch)) => assert_eq!(ch, CHANNELS),
+            res => panic!("Unexpected result {:?}", res.map(|(r, _)| r)),
+        }
+        let n_pending = env.eventgen.len();
+        let (_, next_event) = env.step(event.clone(), Some(3)).unwrap();
+        // The channel is now in use
+        match env.step(event.clone(), Some(3)) {
+            Err(EnvError::ChannelInUse(_, 3)) => {}
+            res => panic!("Unexpected result {:?}", res.map(|(r, _)| r)),
+        }
+        let end = Event {
+            etype: EType::END,
+            ch: Some(3),
+            ..event.clone()
+        };
+        for action in &[None, Some(4)] {
+            assert!(env.step(end.clone(), *action).is_err());
+        }
+        // Channel 3 is not eligible within the reuse distance of 'cell'
+        let arrival = Event {
+            cell: cell_of(neighbors(1, cell.row, cell.col, false), 0),
+            ..event
+        };
+        match env.step(arrival, Some(3)) {
+            Err(EnvError::IneligibleChannel(_, 3)) => {}
+            res => panic!("Unexpected result {:?}", res.map(|(r, _)| r)),
         }
+        assert_eq!(get_inuse_chs(&env.grid, &cell), vec![3]);
+        assert_eq!(env.frep, feature_rep(&env.grid));
+        // Rejected actions are not counted as substituted illegal actions
+        assert_eq!(env.stats.n_illegal_actions(), 0);
+        // A new call and its end event were generated by the valid step only
+        assert_eq!(env.eventgen.len(), n_pending + 1);
+        assert!(env.eventgen.end_event(&cell, 3).is_some());
+        let action = match next_event.etype {
+            EType::END => next_event.ch,
+            _ => None,
+        };
+        env.step(next_event, action).unwrap();
+    }
+
+    #[test]
+    /// Illegal actions are substituted according to the illegal action policy, and counted
+    fn test_illegal_action_policies() {
+        for policy in &["Block", "Fallback"] {
+            let (mut env, event) = Env::new(&Opt::from_iter(&["DCA", "--illegal_action", policy]));
+            let cell = event.cell.clone();
+            env.step(event.clone(), Some(3)).unwrap();
+            // Channel 3 is not eligible within the reuse distance of 'cell'
+            let neigh = cell_of(neighbors(1, cell.row, cell.col, false), 0);
+            let arrival = Event {
+                cell: neigh.clone(),
+                ..event.clone()
+            };
+            env.step(arrival, Some(3)).unwrap();
+            let expected = if *policy == "Block" { vec![] } else { vec![0] };
+            assert_eq!(get_inuse_chs(&env.grid, &neigh), expected);
+            assert_eq!(env.substituted, Some(expected.first().cloned()));
+            // An end event frees the channel of the ending call instead of channel 7
+            let end = Event {
+                etype: EType::END,
+                ch: Some(3),
+                ..event
+            };
+            env.step(end, Some(7)).unwrap();
+            assert!(get_inuse_chs(&env.grid, &cell).is_empty());
+            assert_eq!(env.substituted, Some(Some(3)));
+            assert_eq!(env.stats.n_illegal_actions(), 2);
+            assert_eq!(env.frep, feature_rep(&env.grid));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Illegal action")]
+    fn test_illegal_action_panic() {
+        let (mut env, event) = Env::new(&Opt::from_iter(&["DCA", "--illegal_action", "Panic"]));
+        let _ = env.step(event, Some(CHANNELS));
     }
 }
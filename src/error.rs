@@ -0,0 +1,66 @@
+use eventgen::Event;
+use gridfuncs::Cell;
+use std::error;
+use std::fmt;
+
+/// Invalid actions, and violated invariants of the environment
+#[derive(Debug, Clone)]
+pub enum EnvError {
+    /// The action is not a channel
+    InvalidChannel(usize),
+    /// No channel was given to free on an end event
+    MissingAction(Event),
+    /// An end event without the channel of the call
+    MissingEndChannel(Event),
+    /// A call arrival was assigned a channel already in use in its cell
+    ChannelInUse(Cell, usize),
+    /// A call arrival was assigned a channel in use within the reuse distance of its cell
+    IneligibleChannel(Cell, usize),
+    /// A channel to free or reassign is not in use in its cell
+    ChannelNotInUse(Cell, usize),
+    /// The channel reuse constraint is violated after executing an action
+    ReuseConstraint(String),
+    /// There is no pending end event for the call on a channel in a cell
+    MissingEndEvent(Cell, usize),
+    /// There are no pending events
+    NoEvents,
+    /// The incrementally updated feature representation differs from that of the grid
+    FrepMismatch,
+    /// The number of calls in progress differs from the number of accepted calls that
+    /// have not ended
+    CallCountMismatch { expected: i32, found: usize },
+}
+
+impl fmt::Display for EnvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EnvError::InvalidChannel(ch) => write!(f, "Invalid channel: {}", ch),
+            EnvError::MissingAction(ref event) => {
+                write!(f, "No channel to free for end event {:?}", event)
+            }
+            EnvError::MissingEndChannel(ref event) => write!(f, "No CH for end event {:?}", event),
+            EnvError::ChannelInUse(ref cell, ch) => {
+                write!(f, "Channel {} already in use in {:?}", ch, cell)
+            }
+            EnvError::IneligibleChannel(ref cell, ch) => {
+                write!(f, "Channel {} not eligible in {:?}", ch, cell)
+            }
+            EnvError::ChannelNotInUse(ref cell, ch) => {
+                write!(f, "Channel {} not in use in {:?}", ch, cell)
+            }
+            EnvError::ReuseConstraint(ref msg) => write!(f, "{}", msg),
+            EnvError::MissingEndEvent(ref cell, ch) => {
+                write!(f, "No end event for channel {} in {:?}", ch, cell)
+            }
+            EnvError::NoEvents => write!(f, "No events to pop"),
+            EnvError::FrepMismatch => write!(f, "Incremental frep differs from frep of grid"),
+            EnvError::CallCountMismatch { expected, found } => write!(
+                f,
+                "Expected {} calls in progress, found {}",
+                expected, found
+            ),
+        }
+    }
+}
+
+impl error::Error for EnvError {}
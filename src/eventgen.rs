@@ -1,11 +1,9 @@
 use super::Opt;
+use error::EnvError;
 use gridfuncs::*;
 use ordered_float::*;
 use rand::distributions::{Distribution, Exp, Uniform};
-use rand::thread_rng;
-use revord::RevOrd;
-use std::cmp::Ordering;
-use std::collections::BinaryHeap;
+use random::rng;
 use std::collections::HashMap;
 use std::fmt;
 
@@ -38,45 +36,195 @@ pub struct Event {
 }
 
 /// Event Identifiers
+// Ordered by time, then by ID so that of two events with the same time stamp,
+// the one generated first is handled first
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
 struct EI {
     // floats must be wrapped in NotNaN to support ordering (impl Ord)
-    // NotNaNs must be wrapped in RevOrd which reveres ordering since BinaryHeap sorts by max
-    time: RevOrd<NotNaN<f64>>,
-    id: RevOrd<u32>,
+    time: NotNaN<f64>,
+    id: u32,
 }
 
-impl Ord for EI {
-    fn cmp(&self, other: &EI) -> Ordering {
-        let o = self.time.cmp(&other.time);
-        match o {
-            Ordering::Equal => self.id.cmp(&other.id),
-            _ => o,
+// Marks a cell-channel pair without an end event in the queue
+const NO_POS: usize = usize::MAX;
+
+/// A binary min-heap of events, stored inline and sorted on their identifiers, along with
+/// the heap position of each event by ID and of the end event of each cell-channel pair.
+/// Event IDs must be unique among the pending events.
+struct EventQueue {
+    heap: Vec<(EI, Event)>,
+    id_pos: HashMap<u32, usize>,
+    end_pos: Vec<usize>,
+}
+
+impl Default for EventQueue {
+    fn default() -> Self {
+        EventQueue {
+            heap: Vec::new(),
+            id_pos: HashMap::new(),
+            end_pos: vec![NO_POS; ROWS * COLS * CHANNELS],
         }
     }
 }
 
-impl PartialOrd for EI {
-    fn partial_cmp(&self, other: &EI) -> Option<Ordering> {
-        Some(self.cmp(other))
-    }
+/// Index of a cell-channel pair into 'EventQueue::end_pos'
+fn end_idx(cell: &Cell, ch: usize) -> usize {
+    (cell.row * COLS + cell.col) * CHANNELS + ch
 }
 
-impl PartialEq for EI {
-    fn eq(&self, other: &EI) -> bool {
-        self.id == other.id
+impl EventQueue {
+    fn push(&mut self, event: Event) {
+        unsafe {
+            // 'event.time' was just generated by one of the 'event_*' functions
+            // and cannot be NaN
+            let ei = EI {
+                time: NotNaN::unchecked_new(event.time),
+                id: event.id,
+            };
+            self.heap.push((ei, event));
+        }
+        let i = self.heap.len() - 1;
+        self.set_pos(i);
+        self.sift_up(i);
+    }
+
+    fn pop(&mut self) -> Option<Event> {
+        if self.heap.is_empty() {
+            None
+        } else {
+            Some(self.remove(0))
+        }
+    }
+
+    /// Remove the event at heap position 'i'
+    fn remove(&mut self, i: usize) -> Event {
+        let (_, event) = self.heap.swap_remove(i);
+        self.id_pos.remove(&event.id);
+        if event.etype == EType::END {
+            self.end_pos[end_idx(&event.cell, event.ch.expect("No CH for end event"))] = NO_POS;
+        }
+        if i < self.heap.len() {
+            // The last event, now at 'i', may belong either above or below it
+            self.set_pos(i);
+            self.sift_up(i);
+            self.sift_down(i);
+        }
+        event
+    }
+
+    /// Heap position of the event with ID 'id'
+    fn position(&self, id: u32) -> Option<usize> {
+        self.id_pos.get(&id).cloned()
+    }
+
+    /// Change the time of the event at heap position 'i'
+    fn reschedule(&mut self, i: usize, time: NotNaN<f64>) {
+        self.heap[i].0.time = time;
+        self.heap[i].1.time = time.into_inner();
+        self.sift_up(i);
+        self.sift_down(i);
+    }
+
+    /// The end event of the call on channel 'ch' in 'cell'
+    fn end_event(&self, cell: &Cell, ch: usize) -> Option<&Event> {
+        match self.end_pos[end_idx(cell, ch)] {
+            NO_POS => None,
+            i => Some(&self.heap[i].1),
+        }
+    }
+
+    /// The events that satisfy 'pred', in the order they will be popped.
+    /// This filters and sorts the whole heap.
+    fn sorted<P: Fn(&Event) -> bool>(&self, pred: P) -> Vec<&Event> {
+        self.sorted_at(
+            (0..self.heap.len())
+                .filter(|&i| pred(&self.heap[i].1))
+                .collect(),
+        )
+    }
+
+    /// The end events of calls on channel 'ch', in any cell, in the order they will be popped
+    fn ch_end_events(&self, ch: usize) -> Vec<&Event> {
+        let positions = (0..ROWS * COLS)
+            .map(|cell_idx| self.end_pos[cell_idx * CHANNELS + ch])
+            .filter(|&i| i != NO_POS)
+            .collect();
+        self.sorted_at(positions)
+    }
+
+    /// The events at heap positions 'positions', in the order they will be popped
+    fn sorted_at(&self, mut positions: Vec<usize>) -> Vec<&Event> {
+        positions.sort_unstable_by_key(|&i| self.heap[i].0);
+        positions.into_iter().map(|i| &self.heap[i].1).collect()
+    }
+
+    /// Change the channel of the end event of the call on channel 'from_ch' in 'cell'
+    fn reassign(&mut self, cell: &Cell, from_ch: usize, to_ch: usize) -> Option<()> {
+        let i = self.end_pos[end_idx(cell, from_ch)];
+        if i == NO_POS {
+            return None;
+        }
+        self.end_pos[end_idx(cell, from_ch)] = NO_POS;
+        self.heap[i].1.ch = Some(to_ch);
+        self.set_pos(i);
+        Some(())
+    }
+
+    /// Record the heap position 'i' of the event at 'i', by ID and, if it is an end event,
+    /// by cell and channel
+    fn set_pos(&mut self, i: usize) {
+        let event = &self.heap[i].1;
+        self.id_pos.insert(event.id, i);
+        if event.etype == EType::END {
+            let idx = end_idx(&event.cell, event.ch.expect("No CH for end event"));
+            self.end_pos[idx] = i;
+        }
+    }
+
+    fn swap(&mut self, i: usize, j: usize) {
+        self.heap.swap(i, j);
+        self.set_pos(i);
+        self.set_pos(j);
+    }
+
+    fn sift_up(&mut self, mut i: usize) {
+        while i > 0 {
+            let parent = (i - 1) / 2;
+            if self.heap[i].0 >= self.heap[parent].0 {
+                break;
+            }
+            self.swap(i, parent);
+            i = parent;
+        }
+    }
+
+    fn sift_down(&mut self, mut i: usize) {
+        let n = self.heap.len();
+        loop {
+            let (left, right) = (2 * i + 1, 2 * i + 2);
+            let mut min = i;
+            if left < n && self.heap[left].0 < self.heap[min].0 {
+                min = left;
+            }
+            if right < n && self.heap[right].0 < self.heap[min].0 {
+                min = right;
+            }
+            if min == i {
+                break;
+            }
+            self.swap(i, min);
+            i = min;
+        }
     }
 }
-impl Eq for EI {}
 
 #[derive(Default)]
 pub struct EventGen {
-    id: u32,                                      // Current Event ID
-    call_rate: f32,                               // Call rate, calls per minutes
-    call_dur_inv: f32,                            // (Inverse of) Average call duration, minutes
+    id: u32,                // Current Event ID
+    call_rate: f32,         // Call rate, calls per minutes
+    call_dur_inv: f32,      // (Inverse of) Average call duration, minutes
     hoff_call_dur_inv: f32, // (Inverse of) Average hand-off call duration, minutes
-    event_pq: BinaryHeap<EI>, // Min-heap of event-identifiers sorted on event times
-    events: HashMap<u32, Event>, // Mapping from event IDs to event structs
-    end_ids: HashMap<(usize, usize, usize), u32>, // Mapping from cell-channel pairs to end event IDs
+    event_pq: EventQueue,   // Min-heap of events sorted on event times
 }
 
 impl EventGen {
@@ -87,7 +235,7 @@ impl EventGen {
             call_rate, opt.call_dur
         );
         EventGen {
-            call_rate: call_rate,
+            call_rate,
             call_dur_inv: 1.0 / opt.call_dur,
             hoff_call_dur_inv: 1.0 / opt.hoff_call_dur,
             ..Default::default()
@@ -96,61 +244,79 @@ impl EventGen {
 
     pub fn push(&mut self, event: Event) {
         debug!("Pushed event: {:?}", event);
-        if event.etype == EType::END {
-            let c = event.cell.clone();
-            self.end_ids.insert(
-                (c.row, c.col, event.ch.expect("No CH for end event").clone()),
-                event.id.clone(),
-            );
-        }
-        unsafe {
-            // 'event.time' was just generated by one of the 'event_*' functions
-            // and cannot be NaN
-            let t: NotNaN<f64> = NotNaN::unchecked_new(event.time.clone());
-            self.event_pq.push(EI {
-                time: RevOrd { 0: t },
-                id: RevOrd {
-                    0: event.id.clone(),
-                },
-            });
-        }
-        self.events.insert(event.id, event);
-    }
-
-    pub fn pop(&mut self) -> Event {
-        let ei = self.event_pq.pop().expect("No events to pop");
-        let event = self.events
-            .remove(&ei.id.0)
-            .expect("Event for ID not found");
-        if event.etype == EType::END {
-            self.end_ids
-                .remove(&(
-                    event.cell.row,
-                    event.cell.col,
-                    event.ch.expect("No CH for end event"),
-                ))
-                .expect("End ID not found");
-        }
-        event
+        self.event_pq.push(event);
+    }
+
+    pub fn pop(&mut self) -> Result<Event, EnvError> {
+        self.event_pq.pop().ok_or(EnvError::NoEvents)
+    }
+
+    /// Move the end event of the call on channel 'from_ch' in 'cell' to channel 'to_ch'
+    pub fn reassign(&mut self, cell: Cell, from_ch: usize, to_ch: usize) -> Result<(), EnvError> {
+        self.event_pq
+            .reassign(&cell, from_ch, to_ch)
+            .ok_or(EnvError::MissingEndEvent(cell, from_ch))
+    }
+
+    /// Remove the pending event with ID 'id' and return it, or None if there is none.
+    /// Takes O(log n) time for n pending events.
+    /// Cancelling a hand-off departure does not cancel the subsequent arrival.
+    pub fn cancel(&mut self, id: u32) -> Option<Event> {
+        self.event_pq.position(id).map(|i| self.event_pq.remove(i))
+    }
+
+    /// Move the pending event with ID 'id' to 'time' and return its previous time,
+    /// or None if there is no such event or 'time' is NaN. Of events with the same time,
+    /// those with the lowest ID are still handled first. Takes O(log n) time for n pending
+    /// events.
+    pub fn reschedule(&mut self, id: u32, time: f64) -> Option<f64> {
+        let time = NotNaN::new(time).ok()?;
+        let i = self.event_pq.position(id)?;
+        let prev_time = self.event_pq.heap[i].1.time;
+        self.event_pq.reschedule(i, time);
+        Some(prev_time)
+    }
+
+    /// The pending end event of the call on channel 'ch' in 'cell'
+    pub fn end_event(&self, cell: &Cell, ch: usize) -> Option<&Event> {
+        self.event_pq.end_event(cell, ch)
+    }
+
+    /// The pending events of 'cell' (hand-off arrivals included), in time order.
+    /// Events are not indexed by cell, so this takes O(n log n) time for n pending events
+    /// and is not meant for use in every simulation step.
+    pub fn cell_events(&self, cell: &Cell) -> Vec<&Event> {
+        self.event_pq.sorted(|event| event.cell == *cell)
+    }
+
+    /// The pending end events of calls on channel 'ch', in any cell, in time order
+    pub fn ch_events(&self, ch: usize) -> Vec<&Event> {
+        self.event_pq.ch_end_events(ch)
+    }
+
+    /// All pending events, in the order they will be popped. Takes O(n log n) time
+    /// for n pending events.
+    pub fn pending(&self) -> Vec<&Event> {
+        self.event_pq.sorted(|_| true)
     }
 
-    pub fn reassign(&mut self, cell: Cell, from_ch: usize, to_ch: usize) {
-        assert_ne!(from_ch, to_ch);
-        let id = self.end_ids
-            .remove(&(cell.row, cell.col, from_ch))
-            .expect("End ID not found");
-        self.end_ids.insert((cell.row, cell.col, to_ch), id);
-        self.events.get_mut(&id).expect("Event for ID not found").ch = Some(to_ch);
+    /// Number of pending events
+    pub fn len(&self) -> usize {
+        self.event_pq.heap.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.event_pq.heap.is_empty()
     }
 
     pub fn event_new(&mut self, t: f64, cell: Cell) {
-        let dt = Exp::new(self.call_rate.into()).sample(&mut thread_rng()) as f64;
+        let dt = Exp::new(self.call_rate.into()).sample(&mut rng());
         self.id += 1;
         let event = Event {
             id: self.id,
             time: t + dt,
             etype: EType::NEW,
-            cell: cell,
+            cell,
             ch: None,
             to_cell: None,
         };
@@ -163,7 +329,7 @@ impl EventGen {
     /// since the ID of the arrival is larger it will be handled last.
     pub fn event_hoff_new(&mut self, t: f64, cell: Cell, ch: usize) {
         let neighs = neighbors(1, cell.row, cell.col, false);
-        let neigh_i: usize = Uniform::from(0..neighs.rows()).sample(&mut thread_rng());
+        let neigh_i: usize = Uniform::from(0..neighs.rows()).sample(&mut rng());
         let to_cell = cell_of(neighs, neigh_i);
         let dur_inv = self.call_dur_inv.into();
         let end_t = self._event_end(t, dur_inv, cell, ch, Some(to_cell.clone()));
@@ -199,17 +365,116 @@ impl EventGen {
         ch: usize,
         to_cell: Option<Cell>,
     ) -> f64 {
-        let dt = Exp::new(dur_inv).sample(&mut thread_rng()) as f64;
+        let dt = Exp::new(dur_inv).sample(&mut rng());
         self.id += 1;
         let event = Event {
             id: self.id,
             time: t + dt,
             etype: EType::END,
-            cell: cell,
+            cell,
             ch: Some(ch),
-            to_cell: to_cell,
+            to_cell,
         };
         self.push(event);
         t + dt
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use eventgen::*;
+    use rand::Rng;
+    use random;
+    use structopt::StructOpt;
+    use Opt;
+
+    fn event(id: u32, time: f64, etype: EType, ch: Option<usize>) -> Event {
+        Event {
+            id,
+            time,
+            etype,
+            cell: Cell { row: 2, col: 3 },
+            ch,
+            to_cell: None,
+        }
+    }
+
+    #[test]
+    /// Events are popped by time, and by ID for equal times, and end events can be
+    /// reassigned wherever they are in the heap
+    fn test_event_queue() {
+        random::seed(0);
+        let mut rng = random::rng();
+        let mut pq = EventQueue::default();
+        let mut expected = Vec::new();
+        for id in 0..CHANNELS as u32 {
+            // Few distinct times, to have many ties
+            let time = f64::from(rng.gen_range(0, 10));
+            let etype = if id % 2 == 0 { EType::NEW } else { EType::END };
+            let ch = if etype == EType::END {
+                Some(id as usize)
+            } else {
+                None
+            };
+            pq.push(event(id, time, etype, ch));
+            expected.push((time, id));
+        }
+        // Move the end event on channel 'ch' to channel 'ch - 1'
+        for ch in (1..CHANNELS).filter(|ch| ch % 2 == 1) {
+            assert_eq!(pq.reassign(&Cell { row: 2, col: 3 }, ch, ch - 1), Some(()));
+        }
+        assert_eq!(pq.reassign(&Cell { row: 2, col: 3 }, 1, 0), None);
+        expected.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        for &(time, id) in &expected {
+            let event = pq.pop().unwrap();
+            assert_eq!((event.time, event.id), (time, id));
+            if event.etype == EType::END {
+                assert_eq!(event.ch, Some(id as usize - 1));
+            }
+        }
+        assert!(pq.pop().is_none());
+        assert!(pq.end_pos.iter().all(|&pos| pos == NO_POS));
+        assert!(pq.id_pos.is_empty());
+    }
+
+    #[test]
+    /// Cancelled events are not popped, and rescheduled events are popped at their new time
+    fn test_cancel_reschedule() {
+        let mut eventgen = EventGen::new(&Opt::from_iter(&["DCA"]));
+        let other = Cell { row: 0, col: 0 };
+        for id in 0..6 {
+            eventgen.push(event(id, f64::from(id), EType::END, Some(id as usize)));
+        }
+        let mut hoff = event(6, 1.0, EType::HOFF, None);
+        hoff.cell = other.clone();
+        eventgen.push(hoff);
+        let mut other_end = event(7, 0.5, EType::END, Some(5));
+        other_end.cell = other.clone();
+        eventgen.push(other_end);
+        assert_eq!(eventgen.len(), 8);
+
+        assert_eq!(eventgen.cancel(2).map(|e| e.id), Some(2));
+        assert!(eventgen.cancel(2).is_none());
+        assert!(eventgen.end_event(&Cell { row: 2, col: 3 }, 2).is_none());
+        // Move event 0 after event 4, and event 5 to the time of event 1, which it
+        // follows since it has the higher ID
+        assert_eq!(eventgen.reschedule(0, 4.5), Some(0.0));
+        assert_eq!(eventgen.reschedule(5, 1.0), Some(5.0));
+        assert!(eventgen.reschedule(2, 1.0).is_none());
+        assert!(eventgen.reschedule(3, f64::NAN).is_none());
+
+        let ids = |events: Vec<&Event>| events.iter().map(|e| e.id).collect::<Vec<_>>();
+        assert_eq!(ids(eventgen.pending()), vec![7, 1, 5, 6, 3, 4, 0]);
+        assert_eq!(ids(eventgen.cell_events(&other)), vec![7, 6]);
+        assert_eq!(ids(eventgen.ch_events(5)), vec![7, 5]);
+        assert_eq!(
+            eventgen
+                .end_event(&Cell { row: 2, col: 3 }, 0)
+                .map(|e| e.time),
+            Some(4.5)
+        );
+        let popped: Vec<u32> = (0..7).map(|_| eventgen.pop().unwrap().id).collect();
+        assert_eq!(popped, vec![7, 1, 5, 6, 3, 4, 0]);
+        assert!(eventgen.is_empty());
+    }
+}
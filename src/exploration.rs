@@ -0,0 +1,118 @@
+use gridfuncs::argpmax1;
+use ndarray::Array1;
+use rand::Rng;
+use random::rng;
+use Opt;
+
+arg_enum! {
+    /// Action selection policies:
+    /// - Greedy: Always select the action with the highest q-value
+    /// - EpsGreedy: Select a uniformly random action with probability 'epsilon', which decays
+    ///   by a factor of 'epsilon_decay' each selection, and the greedy action otherwise
+    /// - Boltzmann: Sample actions from a softmax over q-values with temperature 'temp',
+    ///   which decays by a factor of 'temp_decay' each selection
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum ExplorationKind {
+        Greedy,
+        EpsGreedy,
+        Boltzmann
+    }
+}
+
+pub struct Exploration {
+    kind: ExplorationKind,
+    epsilon: f32,
+    epsilon_decay: f32,
+    temp: f32,
+    temp_decay: f32,
+}
+
+impl Exploration {
+    pub fn new(opt: &Opt) -> Self {
+        Exploration {
+            kind: opt.exploration,
+            epsilon: opt.epsilon,
+            epsilon_decay: opt.epsilon_decay,
+            temp: opt.temp,
+            temp_decay: opt.temp_decay,
+        }
+    }
+
+    /// Select the index of an action given the q-value of each action, of which
+    /// there must be at least one. Decays the exploration rate.
+    pub fn select(&mut self, qvals: &Array1<f32>) -> usize {
+        let (greedy_idx, _) = argpmax1(qvals).expect("No actions to select from");
+        match self.kind {
+            ExplorationKind::Greedy => greedy_idx,
+            ExplorationKind::EpsGreedy => {
+                let epsilon = self.epsilon;
+                self.epsilon *= self.epsilon_decay;
+                let mut rng = rng();
+                if rng.gen::<f32>() < epsilon {
+                    rng.gen_range(0, qvals.len())
+                } else {
+                    greedy_idx
+                }
+            }
+            ExplorationKind::Boltzmann => {
+                let probs = softmax(qvals, self.temp);
+                self.temp *= self.temp_decay;
+                let u = rng().gen::<f32>();
+                let mut cum_prob = 0.0;
+                for (idx, &prob) in probs.iter().enumerate() {
+                    cum_prob += prob;
+                    if u < cum_prob {
+                        return idx;
+                    }
+                }
+                // Rounding errors may leave the probabilities summing to slightly below 1
+                greedy_idx
+            }
+        }
+    }
+}
+
+/// Action probabilities for Boltzmann exploration with temperature 'temp'
+fn softmax(qvals: &Array1<f32>, temp: f32) -> Array1<f32> {
+    // Subtracting the max q-value avoids overflow and does not change the probabilities
+    let max = qvals.fold(f32::MIN, |max, &q| max.max(q));
+    let exps = qvals.mapv(|q| ((q - max) / temp).exp());
+    let sum = exps.scalar_sum();
+    exps / sum
+}
+
+#[cfg(test)]
+mod tests {
+    use exploration::*;
+    use structopt::StructOpt;
+
+    #[test]
+    fn test_softmax() {
+        let probs = softmax(&array![1.0, 2.0, 3.0], 1.0);
+        assert!((probs.scalar_sum() - 1.0).abs() < 1e-6);
+        assert!(probs[[0]] < probs[[1]] && probs[[1]] < probs[[2]]);
+        // Large q-values do not overflow, and a low temperature is close to greedy
+        let probs = softmax(&array![1000.0, 1001.0], 0.01);
+        assert!(probs[[1]] > 0.999);
+    }
+
+    #[test]
+    fn test_epsilon_decay() {
+        let opt = Opt::from_iter(&[
+            "DCA",
+            "--exploration",
+            "epsgreedy",
+            "--epsilon",
+            "1",
+            "--epsilon_decay",
+            "0",
+        ]);
+        let mut explore = Exploration::new(&opt);
+        let qvals = array![0.0, 1.0, 0.0];
+        explore.select(&qvals);
+        // With epsilon decayed to zero, the selection is greedy
+        for _ in 0..10 {
+            assert_eq!(explore.select(&qvals), 1);
+        }
+    }
+}
@@ -1,23 +1,23 @@
 use eventgen::EType;
 use ndarray::prelude::*;
 use ndarray::{Data, DataMut};
+use std::iter;
 use std::ops::AddAssign;
-use std::ops::BitAnd;
-use std::ops::BitOr;
-use std::ops::BitOrAssign;
-use std::ops::Not;
 
 pub const ROWS: usize = 7;
 pub const COLS: usize = 7;
 pub const CHANNELS: usize = 70;
 
-// TODO NOTE
-// Consider doing all ops on freps as usize and casting to f32 at end only
+// (NEIGHS1, NEIGHS2, NEIGHS4, N_NEIGHS)
+type Neighs = (
+    Array<usize, Ix4>,
+    Array<usize, Ix4>,
+    Array<usize, Ix4>,
+    Array<usize, Ix3>,
+);
 
 lazy_static! {
-    // (NEIGHS1, NEIGHS2, NEIGHS4, N_NEIGHS):
-    static ref NEIGHS: (Array<usize, Ix4>, Array<usize, Ix4>,
-                        Array<usize, Ix4>, Array<usize, Ix3>) = generate_neighs();
+    static ref NEIGHS: Neighs = generate_neighs();
 }
 
 #[derive(Eq, PartialEq, Hash, Clone, Debug)]
@@ -26,12 +26,77 @@ pub struct Cell {
     pub col: usize,
 }
 
-pub type Grid<S: Data<Elem = bool>> = ArrayBase<S, Ix3>;
+pub type Grid<S> = ArrayBase<S, Ix3>;
 pub type GridO = Array<bool, Ix3>;
-pub type GridsO = Array<bool, Ix4>;
-pub type Frep<S: Data<Elem = f32>> = ArrayBase<S, Ix3>;
-pub type FrepO = Array<f32, Ix3>;
-pub type FrepsO = Array<f32, Ix4>;
+pub type Frep<S> = ArrayBase<S, Ix3>;
+// Features are counts of at most 'CHANNELS', and are converted to floating point
+// only as input to a network
+pub type FrepO = Array<u8, Ix3>;
+pub type FrepsO = Array<u8, Ix4>;
+/// Sparse difference between two freps: (row, col, feature, change) for each changed feature
+pub type FrepDiff = Vec<(usize, usize, usize, i8)>;
+
+/// A set of channels, with bit 'ch' set if channel 'ch' is in the set
+pub type ChSet = u128;
+
+/// The channels in use at each cell, as a bitset for each cell.
+/// Requires that 'CHANNELS' is no more than 128.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct BitGrid([ChSet; ROWS * COLS]);
+
+impl Default for BitGrid {
+    fn default() -> Self {
+        BitGrid([0; ROWS * COLS])
+    }
+}
+
+impl BitGrid {
+    /// Pack a grid of shape (ROWS, COLS, CHANNELS)
+    pub fn from_grid<S: Data<Elem = bool>>(grid: &Grid<S>) -> Self {
+        let mut bgrid = BitGrid::default();
+        for ((r, c, ch), &inuse) in grid.indexed_iter() {
+            bgrid.set(r, c, ch, inuse);
+        }
+        bgrid
+    }
+
+    /// Unpack into a grid of shape (ROWS, COLS, CHANNELS)
+    pub fn to_grid(&self) -> GridO {
+        Array::from_shape_fn((ROWS, COLS, CHANNELS), |(r, c, ch)| self.get(r, c, ch))
+    }
+
+    /// The channels in use at cell (row, col)
+    pub fn chs(&self, row: usize, col: usize) -> ChSet {
+        self.0[row * COLS + col]
+    }
+
+    pub fn get(&self, row: usize, col: usize, ch: usize) -> bool {
+        self.chs(row, col) & (1 << ch) != 0
+    }
+
+    pub fn set(&mut self, row: usize, col: usize, ch: usize, inuse: bool) {
+        let chs = &mut self.0[row * COLS + col];
+        if inuse {
+            *chs |= 1 << ch;
+        } else {
+            *chs &= !(1 << ch);
+        }
+    }
+}
+
+/// The channels in a channel set, in ascending order
+pub fn iter_chs(chs: ChSet) -> impl Iterator<Item = usize> {
+    let mut chs = chs;
+    iter::from_fn(move || {
+        if chs == 0 {
+            None
+        } else {
+            let ch = chs.trailing_zeros() as usize;
+            chs &= chs - 1;
+            Some(ch)
+        }
+    })
+}
 
 /// Distance from cell (r1, c1) to cell (r2, c2) in a hexagonal grid
 fn hex_distance(r1: i8, c1: i8, r2: i8, c2: i8) -> i8 {
@@ -44,12 +109,7 @@ fn hex_distance(r1: i8, c1: i8, r2: i8, c2: i8) -> i8 {
 /// Therefore, a (3 x Rows x Cols) array is also returned, which contains,
 /// for each of the distances above, for each cell, the number of neighbors with the given
 /// distance or less.
-fn generate_neighs() -> (
-    Array<usize, Ix4>,
-    Array<usize, Ix4>,
-    Array<usize, Ix4>,
-    Array<usize, Ix3>,
-) {
+fn generate_neighs() -> Neighs {
     // Indecies of neighbors with distance of 1 or less
     let mut neighs1 = Array::zeros((ROWS, COLS, 7, 2));
     // Indecies of neighbors with distance of 2 or less
@@ -113,8 +173,8 @@ pub fn neighbors(
     let start = if include_self { 0 } else { 1 };
     let end = NEIGHS.3[[d, row, col]];
     debug_assert_ne!(
-        allneighs.slice(s![row, col, end, ..]),
-        Array::from_vec(vec![0 as usize, 0]),
+        allneighs.slice(s![row, col, end - 1, ..]),
+        Array::from_vec(vec![0_usize, 0]),
         "Neighs: {:?}\nEnd: {}",
         allneighs.slice(s![row, col, ..end, ..]),
         end
@@ -130,37 +190,26 @@ pub fn cell_of(neighs: ArrayView<usize, Ix2>, i: usize) -> Cell {
     }
 }
 
-/// Alloc. map of channels in use at cell neighbors with distance of 2 or less
-fn inuse_neighs<S: Data<Elem = bool>>(grid: &Grid<S>, cell: &Cell) -> Array<bool, Ix1> {
-    let neighs = neighbors(2, cell.row, cell.col, false);
-    let mut alloc_map: Array1<bool> = Array::default(CHANNELS);
-    for neigh in neighs.outer_iter() {
-        alloc_map.bitor_assign(&grid.slice(s![neigh[0], neigh[1], ..]));
-    }
-    alloc_map
+/// Channels in use at cell neighbors with distance of 2 or less
+fn inuse_neighs(grid: &BitGrid, cell: &Cell) -> ChSet {
+    neighbors(2, cell.row, cell.col, false)
+        .outer_iter()
+        .fold(0, |inuse, neigh| inuse | grid.chs(neigh[0], neigh[1]))
 }
 
-/// One-hot array of eligible channels
-fn eligible_map<S: Data<Elem = bool>>(grid: &Grid<S>, cell: &Cell) -> Array<bool, Ix1> {
-    inuse_neighs(grid, cell)
-        .bitor(&grid.slice(s![cell.row, cell.col, ..]))
-        .not()
+/// Set of eligible channels
+fn eligible_map(grid: &BitGrid, cell: &Cell) -> ChSet {
+    !(inuse_neighs(grid, cell) | grid.chs(cell.row, cell.col)) & ((1 << CHANNELS) - 1)
 }
 
-pub fn get_inuse_chs<S: Data<Elem = bool>>(grid: &Grid<S>, cell: &Cell) -> Vec<usize> {
-    grid.slice(s![cell.row, cell.col, ..])
-        .indexed_iter()
-        .filter_map(|(index, &item)| if item { Some(index) } else { None })
-        .collect()
+pub fn get_inuse_chs(grid: &BitGrid, cell: &Cell) -> Vec<usize> {
+    iter_chs(grid.chs(cell.row, cell.col)).collect()
 }
 
 /// Return the eligible channels for the given cell. A channel is eligible if it is free at
 /// the cell and all of its neighbors with distance of 2 or less.
-pub fn get_eligible_chs<S: Data<Elem = bool>>(grid: &Grid<S>, cell: &Cell) -> Vec<usize> {
-    eligible_map(grid, cell)
-        .indexed_iter()
-        .filter_map(|(index, &item)| if item { Some(index) } else { None })
-        .collect()
+pub fn get_eligible_chs(grid: &BitGrid, cell: &Cell) -> Vec<usize> {
+    iter_chs(eligible_map(grid, cell)).collect()
 }
 
 /// Return Some(argmax, max) of a 1D array; None if its empty
@@ -175,33 +224,25 @@ pub fn argpmax1<N: PartialOrd + Copy>(arr: &Array1<N>) -> Option<(usize, N)> {
 /// Given a grid 'grid' and a set of actions, the latter specified by a cell, an event type
 /// and a list of channels, return the grids ('afterstates') that would result from
 /// executing each of the actions on 'grid'
-pub fn afterstates<S: Data<Elem = bool>>(
-    grid: &Grid<S>,
-    cell: &Cell,
-    etype: &EType,
-    chs: &[usize],
-) -> GridsO {
-    let targ_val = match etype {
-        EType::END => false,
-        _ => true,
-    };
-    let mut grids: GridsO = Array::default((chs.len(), 7, 7, 70));
-    for (i, ch) in chs.into_iter().enumerate() {
-        grids.slice_mut(s![i, .., .., ..]).assign(&grid);
-        grids[[i, cell.row, cell.col, *ch]] = targ_val;
-    }
-    grids
+pub fn afterstates(grid: &BitGrid, cell: &Cell, etype: &EType, chs: &[usize]) -> Vec<BitGrid> {
+    let targ_val = *etype != EType::END;
+    chs.iter()
+        .map(|&ch| {
+            let mut astate = *grid;
+            astate.set(cell.row, cell.col, ch, targ_val);
+            astate
+        })
+        .collect()
 }
 
 /// Returns an error if the reuse constraint is violated
-pub fn validate_reuse_constraint<S: Data<Elem = bool>>(grid: &Grid<S>) -> Result<(), String> {
+pub fn validate_reuse_constraint(grid: &BitGrid) -> Result<(), String> {
     for r in 0..ROWS {
         for c in 0..COLS {
             let cell = Cell { row: r, col: c };
             // Channels in use at any neighbor within the reuse distance AND the focal cell 'cell'
-            let inuse = inuse_neighs(grid, &cell).bitand(&grid.slice(s![r, c, ..]));
-            debug_assert_eq!(inuse.shape(), &[CHANNELS]);
-            if inuse.into_iter().any(|&x| x) {
+            let inuse = inuse_neighs(grid, &cell) & grid.chs(r, c);
+            if inuse != 0 {
                 return Err(format!(
                     "Reuse constraint violated at (r,c): ({}, {})",
                     r, c
@@ -217,93 +258,133 @@ pub fn validate_reuse_constraint<S: Data<Elem = bool>>(grid: &Grid<S>) -> Result
 /// specifies how many times each of the channels is in used within a 4-cell radius,
 /// not including the cell itself. An additional feature counts the number of eligible
 /// channels in that cell.
-pub fn feature_rep<S: Data<Elem = bool>>(grid: &Grid<S>) -> FrepO {
+pub fn feature_rep(grid: &BitGrid) -> FrepO {
     let mut frep = Array::zeros((ROWS, COLS, CHANNELS + 1));
     for r in 0..ROWS {
         for c in 0..COLS {
-            let mut n_used: Array1<u32> = Array::zeros(CHANNELS);
             for neigh in neighbors(4, r, c, false).outer_iter() {
-                n_used.add_assign(&grid.slice(s![neigh[0], neigh[1], ..]).mapv(|x| x as u32));
+                for ch in iter_chs(grid.chs(neigh[0], neigh[1])) {
+                    frep[[r, c, ch]] += 1;
+                }
             }
-            frep.slice_mut(s![r, c, ..CHANNELS]).assign(&n_used);
             // Find the number of eligible channels for cell (r, c)
-            let elig = eligible_map(&grid, &Cell { row: r, col: c });
-            frep[[r, c, CHANNELS]] = elig.fold(0, |acc, &x| acc + x as u32);
+            frep[[r, c, CHANNELS]] =
+                eligible_map(grid, &Cell { row: r, col: c }).count_ones() as u8;
         }
     }
-    frep.mapv(|x: u32| x as f32)
+    frep
 }
 
-/// Given a grid, its feature representation 'frep',
-/// and a set of actions specified by cell, event type and a list of channels,
-/// derive feature representations for the afterstates of grid.
-/// The grid is only temporarily modified.
-pub fn incremental_freps<S: Data<Elem = f32>, T: Data<Elem = bool> + DataMut>(
-    grid: &mut Grid<T>,
-    frep: &Frep<S>,
-    cell: &Cell,
-    etype: &EType,
-    chs: &[usize],
-) -> FrepsO {
-    // panic!("YIELDS INCORRECT RESULTS");
+/// Given a grid and a set of actions specified by cell, event type and a list of channels,
+/// derive the differences between the feature representation of the grid and those of
+/// its afterstates.
+pub fn frep_diffs(grid: &BitGrid, cell: &Cell, etype: &EType, chs: &[usize]) -> Vec<FrepDiff> {
     let (r1, c1) = (cell.row, cell.col);
     let neighs4 = neighbors(4, r1, c1, false);
     let neighs2 = neighbors(2, r1, c1, true);
-    let mut freps = Array::zeros((chs.len(), ROWS, COLS, CHANNELS + 1));
-    freps.assign(&frep);
-    let mut n_used_neighs_diff: isize = 1;
-    let mut n_elig_self_diff: isize = -1;
+    let (n_used_neighs_diff, n_elig_self_diff) = match *etype {
+        EType::END => (-1, 1),
+        _ => (1, -1),
+    };
+    // A channel changes eligibility at the focal cell and its co-channel neighbors within
+    // the reuse distance (which is 2) if it is eligible once freed at the focal cell,
+    // for end events, or if it is eligible before being assigned, for arrivals
+    let mut grid = *grid;
     if *etype == EType::END {
-        n_used_neighs_diff = -1;
-        n_elig_self_diff = 1;
-
-        for ch in chs.into_iter() {
-            grid[[r1, c1, *ch]] = false;
+        for ch in chs.iter() {
+            grid.set(r1, c1, *ch, false);
         }
     }
+    let eligible: Vec<ChSet> = neighs2
+        .outer_iter()
+        .map(|neigh| {
+            eligible_map(
+                &grid,
+                &Cell {
+                    row: neigh[0],
+                    col: neigh[1],
+                },
+            )
+        })
+        .collect();
 
-    for (i, ch) in chs.into_iter().enumerate() {
-        for neigh in neighs4.outer_iter() {
-            freps[[i, neigh[0], neigh[1], *ch]].add_assign(n_used_neighs_diff as f32);
-        }
-        for neigh_a in neighs2.outer_iter() {
-            let (r2, c2) = (neigh_a[0], neigh_a[1]);
-            let neighs = neighbors(2, r2, c2, false);
-            // A channel is eligible if it is free in the focal cell ('neigh_a')
-            // and every neighbor ('neighs') of the focal cell within the reuse
-            // distance (which is 2). Channels in use are 'True' in the grid map matrix
-            // so folding the bitwise_or operator over (partial) gridmap of the focal cell
-            // and it's co-channel neighbors yields a vector of bits where an index is
-            // 'false' if the channel is eligible.
-            let mut not_eligible = grid[[r2, c2, *ch]];
-            for neigh_b in neighs.outer_iter() {
-                not_eligible |= grid[[neigh_b[0], neigh_b[1], *ch]];
+    chs.iter()
+        .map(|&ch| {
+            let mut diff = Vec::with_capacity(neighs4.rows() + neighs2.rows());
+            for neigh in neighs4.outer_iter() {
+                diff.push((neigh[0], neigh[1], ch, n_used_neighs_diff));
             }
-            if !not_eligible {
-                freps[[i, r2, c2, CHANNELS]].add_assign(n_elig_self_diff as f32);
+            for (neigh, elig) in neighs2.outer_iter().zip(&eligible) {
+                if elig & (1 << ch) != 0 {
+                    diff.push((neigh[0], neigh[1], CHANNELS, n_elig_self_diff));
+                }
             }
-        }
+            diff
+        })
+        .collect()
+}
+
+/// Apply a difference in features to 'frep'
+pub fn apply_diff<S: DataMut<Elem = u8>>(frep: &mut Frep<S>, diff: &[(usize, usize, usize, i8)]) {
+    for &(r, c, f, d) in diff {
+        let feature = &mut frep[[r, c, f]];
+        *feature = (i16::from(*feature) + i16::from(d)) as u8;
     }
+}
 
-    if *etype == EType::END {
-        for ch in chs.into_iter() {
-            grid[[r1, c1, *ch]] = true;
-        }
+/// The feature representations that result from applying each of 'diffs' to 'frep'
+pub fn apply_diffs<S: Data<Elem = u8>>(frep: &Frep<S>, diffs: &[FrepDiff]) -> FrepsO {
+    let mut freps = Array::zeros((diffs.len(), ROWS, COLS, CHANNELS + 1));
+    freps.assign(frep);
+    for (mut afrep, diff) in freps.outer_iter_mut().zip(diffs) {
+        apply_diff(&mut afrep, diff);
     }
     freps
 }
 
-/// Number of channels in use on the whole grid
-pub fn n_used<S: Data<Elem = bool>>(grid: &Grid<S>) -> usize {
-    grid.fold(0, |n_used, &inuse| n_used + inuse as usize)
+/// Given a grid, its feature representation 'frep',
+/// and a set of actions specified by cell, event type and a list of channels,
+/// derive feature representations for the afterstates of grid.
+pub fn incremental_freps<S: Data<Elem = u8>>(
+    grid: &BitGrid,
+    frep: &Frep<S>,
+    cell: &Cell,
+    etype: &EType,
+    chs: &[usize],
+) -> FrepsO {
+    apply_diffs(frep, &frep_diffs(grid, cell, etype, chs))
 }
 
+/// Update 'frep', the feature representation of the grid before channel 'ch' was assigned
+/// or freed at 'cell', to that of 'grid', the grid after the channel change.
+pub fn update_frep<S: DataMut<Elem = u8>>(
+    grid: &BitGrid,
+    frep: &mut Frep<S>,
+    cell: &Cell,
+    ch: usize,
+) {
+    let mut pre_grid = *grid;
+    let (etype, inuse) = if grid.get(cell.row, cell.col, ch) {
+        (EType::NEW, false)
+    } else {
+        (EType::END, true)
+    };
+    pre_grid.set(cell.row, cell.col, ch, inuse);
+    apply_diff(frep, &frep_diffs(&pre_grid, cell, &etype, &[ch])[0]);
+}
+
+/// Number of channels in use on the whole grid
+pub fn n_used(grid: &BitGrid) -> usize {
+    grid.0.iter().map(|chs| chs.count_ones() as usize).sum()
+}
 #[cfg(test)]
 mod tests {
     use gridfuncs::*;
     use itertools::free::zip;
+    use rand::Rng;
+    use random;
 
-    fn eq_frep<S: Data<Elem = f32>, T: Data<Elem = f32>>(frep1: Frep<S>, frep2: Frep<T>) {
+    fn eq_frep<S: Data<Elem = u8>, T: Data<Elem = u8>>(frep1: Frep<S>, frep2: Frep<T>) {
         assert_eq!(frep1.shape(), frep2.shape());
         assert_eq!(frep1.shape(), &[ROWS, COLS, CHANNELS + 1]);
         // Check the equality of feature #1 (number of used chs within reuse dist)
@@ -320,12 +401,12 @@ mod tests {
 
     /// Check that deriving feature reps incrementally yields the same result
     /// as doing it from scratch.
-    fn incremental_vs_scratch(grid: &mut GridO, cell: &Cell, etype: &EType, chs: &[usize]) {
-        let astates = afterstates(&grid, &cell, &etype, &chs);
-        let pre_frep = feature_rep(&grid);
-        let freps_a = incremental_freps(grid, &pre_frep, &cell, &etype, &chs);
-        for (astate, frep_a) in zip(astates.outer_iter(), freps_a.outer_iter()) {
-            let frep_b = feature_rep(&astate);
+    fn incremental_vs_scratch(grid: &BitGrid, cell: &Cell, etype: &EType, chs: &[usize]) {
+        let astates = afterstates(grid, cell, etype, chs);
+        let pre_frep = feature_rep(grid);
+        let freps_a = incremental_freps(grid, &pre_frep, cell, etype, chs);
+        for (astate, frep_a) in zip(&astates, freps_a.outer_iter()) {
+            let frep_b = feature_rep(astate);
             eq_frep(frep_a, frep_b);
         }
     }
@@ -333,12 +414,12 @@ mod tests {
     #[test]
     /// Case: Call arrival on empty grid
     fn test_incremental_1() {
-        let mut grid: GridO = Array3::default((ROWS, COLS, CHANNELS));
-        let grid_original = grid.clone();
+        let grid = BitGrid::default();
+        let grid_original = grid;
         let cell = Cell { row: 2, col: 3 };
         let etype = EType::NEW;
         let chs = get_eligible_chs(&grid, &cell);
-        incremental_vs_scratch(&mut grid, &cell, &etype, &chs);
+        incremental_vs_scratch(&grid, &cell, &etype, &chs);
         // Grid should not have changed
         assert_eq!(grid, grid_original);
     }
@@ -346,43 +427,47 @@ mod tests {
     #[test]
     /// Case: Call termination of the only channel in use
     fn test_incremental_2() {
-        let mut grid: GridO = Array3::default((ROWS, COLS, CHANNELS));
-        grid[[4, 1, 4]] = true;
+        let mut grid = BitGrid::default();
+        grid.set(4, 1, 4, true);
         let cell = Cell { row: 4, col: 1 };
         let etype = EType::END;
         let chs = get_inuse_chs(&grid, &cell);
-        incremental_vs_scratch(&mut grid, &cell, &etype, &chs);
+        incremental_vs_scratch(&grid, &cell, &etype, &chs);
     }
 
     #[test]
     /// Case: Call arrival when focal cell and neighbor has channels in use
     fn test_incremental_3() {
-        let mut grid: GridO = Array3::default((ROWS, COLS, CHANNELS));
+        let mut grid = BitGrid::default();
         let cell = Cell { row: 0, col: 0 };
-        grid[[0, 0, 4]] = true;
-        grid[[0, 1, 5]] = true;
+        grid.set(0, 0, 4, true);
+        grid.set(0, 1, 5, true);
         let etype = EType::NEW;
         let chs = get_eligible_chs(&grid, &cell);
-        incremental_vs_scratch(&mut grid, &cell, &etype, &chs);
+        incremental_vs_scratch(&grid, &cell, &etype, &chs);
     }
 
     #[test]
     fn test_feature_rep1() {
-        let grid: GridO = Array3::default((ROWS, COLS, CHANNELS));
+        let grid = BitGrid::default();
         let frep = feature_rep(&grid);
         // No cell has a channel in use by any of its neighbors
         let f1_target = Array3::zeros((ROWS, COLS, CHANNELS));
         assert_eq!(frep.slice(s![.., .., ..-1]), f1_target);
 
         // No cell has any channels in use, i.e. all are free
-        let f2_target = Array2::from_elem((ROWS, COLS), CHANNELS as f32);
+        let f2_target = Array2::from_elem((ROWS, COLS), CHANNELS as u8);
         assert_eq!(frep.slice(s![.., .., -1]), f2_target);
     }
 
     #[test]
     fn test_feature_rep2() {
-        let mut grid: GridO = Array3::default((ROWS, COLS, CHANNELS));
-        grid.slice_mut(s![.., .., 0]).assign(&array!(true));
+        let mut grid = BitGrid::default();
+        for r in 0..ROWS {
+            for c in 0..COLS {
+                grid.set(r, c, 0, true);
+            }
+        }
         let frep = feature_rep(&grid);
 
         // Every cell has 'n_neighs(cell)' neighbors4 who uses channel 0
@@ -392,36 +477,36 @@ mod tests {
             for c in 0..COLS {
                 // The number of neighs with dist 4 or less
                 let n_neighs = NEIGHS.3[[2, r, c]] - 1;
-                f1_target[[r, c, 0]] = n_neighs as f32;
+                f1_target[[r, c, 0]] = n_neighs as u8;
             }
         }
         assert_eq!(frep.slice(s![.., .., ..-1]), f1_target);
 
         // All cells have the same channel in use, thus all but one of the channels are
         // eligible in all of the cells
-        let f2_target = Array2::from_elem((ROWS, COLS), (CHANNELS - 1) as f32);
+        let f2_target = Array2::from_elem((ROWS, COLS), (CHANNELS - 1) as u8);
         assert_eq!(frep.slice(s![.., .., -1]), f2_target);
     }
 
     #[test]
     fn test_feature_rep3() {
-        let mut grid: GridO = Array3::default((ROWS, COLS, CHANNELS));
+        let mut grid = BitGrid::default();
         let (r, c, ch) = (1, 2, 9);
-        grid[[r, c, ch]] = true;
+        grid.set(r, c, ch, true);
         let frep = feature_rep(&grid);
 
         // Cell (1, 2) has no neighs that use ch9. The neighs of (1, 2)
         // has 1 neigh that use ch9.
         let mut f1_target = Array3::default((ROWS, COLS, CHANNELS));
         for neigh in neighbors(4, r, c, false).outer_iter() {
-            f1_target[[neigh[0], neigh[1], ch]] = 1.0;
+            f1_target[[neigh[0], neigh[1], ch]] = 1;
         }
         assert_eq!(frep.slice(s![.., .., ..-1]), f1_target);
 
         // The interfering neighbors of (row, col) = (1, 2) has one less eligible channel
-        let mut f2_target = Array2::from_elem((ROWS, COLS), CHANNELS as f32);
+        let mut f2_target = Array2::from_elem((ROWS, COLS), CHANNELS as u8);
         for neigh in neighbors(2, r, c, true).outer_iter() {
-            f2_target[[neigh[0], neigh[1]]] -= 1.0;
+            f2_target[[neigh[0], neigh[1]]] -= 1;
         }
         assert_eq!(frep.slice(s![.., .., -1]), f2_target);
     }
@@ -432,11 +517,53 @@ mod tests {
         let (r, c) = (3, 4);
         let chs: [usize; 3] = [0, 4, 10];
         for neigh in neighbors(2, r, c, true).outer_iter() {
-            for ch in chs.into_iter() {
+            for ch in chs.iter() {
                 grid[[neigh[[0]], neigh[[1]], *ch]] = false
             }
         }
-        let elig = get_eligible_chs(&grid, &Cell { row: r, col: c });
+        let elig = get_eligible_chs(&BitGrid::from_grid(&grid), &Cell { row: r, col: c });
         assert_eq!(chs.to_vec(), elig);
     }
+
+    #[test]
+    fn test_bitgrid() {
+        random::seed(0);
+        let mut rng = random::rng();
+        let grid: GridO = Array::from_shape_fn((ROWS, COLS, CHANNELS), |_| rng.gen::<f32>() < 0.1);
+        let bgrid = BitGrid::from_grid(&grid);
+        assert_eq!(bgrid.to_grid(), grid);
+        assert_eq!(n_used(&bgrid), grid.iter().filter(|&&inuse| inuse).count());
+        let cell = Cell { row: 5, col: 2 };
+        let inuse: Vec<usize> = (0..CHANNELS).filter(|&ch| grid[[5, 2, ch]]).collect();
+        assert_eq!(get_inuse_chs(&bgrid, &cell), inuse);
+        // The reuse constraint is violated for a channel in use at a cell and its neighbor
+        let mut bgrid = BitGrid::default();
+        bgrid.set(3, 3, 69, true);
+        bgrid.set(1, 3, 69, true);
+        assert!(validate_reuse_constraint(&bgrid).is_err());
+        bgrid.set(1, 3, 69, false);
+        assert!(validate_reuse_constraint(&bgrid).is_ok());
+        assert_eq!(get_eligible_chs(&bgrid, &cell).len(), CHANNELS - 1);
+    }
+
+    #[test]
+    /// Updating the frep for each channel change yields the frep from scratch
+    fn test_update_frep() {
+        let mut grid = BitGrid::default();
+        let mut frep = feature_rep(&grid);
+        let changes = [
+            (3, 3, 5),
+            (1, 2, 5),
+            (6, 0, 5),
+            (3, 3, 7),
+            (1, 2, 5),
+            (3, 3, 5),
+        ];
+        for &(r, c, ch) in &changes {
+            let inuse = grid.get(r, c, ch);
+            grid.set(r, c, ch, !inuse);
+            update_frep(&grid, &mut frep, &Cell { row: r, col: c }, ch);
+            eq_frep(frep.view(), feature_rep(&grid));
+        }
+    }
 }
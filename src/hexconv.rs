@@ -0,0 +1,526 @@
+use gridfuncs::{neighbors, Frep, CHANNELS, COLS, ROWS};
+use mlp::Activation;
+use ndarray::{Array, Array1, Array2, Array3, ArrayBase, ArrayView2, Axis, Data, Dimension};
+use ndarray::{Ix1, Ix3, Ix4};
+use npy::{param_path, read_npy_shaped, write_npy};
+use optim::{Optimizer, Schedule};
+use rand::distributions::{Distribution, Uniform};
+use random::rng;
+use std::io;
+use std::ops::{AddAssign, MulAssign};
+use std::path::Path;
+use vnet_agent::{batch_td_errs, net_input, Net};
+use Opt;
+
+const N_CELLS: usize = ROWS * COLS;
+
+/// Offsets (row, col) from a cell to itself and to each of its 6 neighbors in the hexagonal grid.
+/// A convolution kernel has separate weights for each of these directions.
+const DIRS: [(isize, isize); 7] = [(0, 0), (-1, 0), (-1, 1), (0, -1), (0, 1), (1, -1), (1, 0)];
+
+lazy_static! {
+    // For each direction in 'DIRS', the (cell, neighbor) pairs of flat cell indecies
+    // where 'neighbor' lies in that direction from 'cell'
+    static ref TAPS: Vec<Vec<(usize, usize)>> = generate_taps();
+}
+
+fn generate_taps() -> Vec<Vec<(usize, usize)>> {
+    let mut taps = vec![Vec::new(); DIRS.len()];
+    for r in 0..ROWS {
+        for c in 0..COLS {
+            for neigh in neighbors(1, r, c, true).outer_iter() {
+                let offset = (
+                    neigh[0] as isize - r as isize,
+                    neigh[1] as isize - c as isize,
+                );
+                let d = DIRS
+                    .iter()
+                    .position(|&dir| dir == offset)
+                    .expect("Neighbor is not adjacent");
+                taps[d].push((r * COLS + c, neigh[0] * COLS + neigh[1]));
+            }
+        }
+    }
+    taps
+}
+
+/// A convolution over each cell and its adjacent cells
+struct ConvLayer {
+    weights: Array3<f32>, // (direction, n_in, n_out)
+    bias: Array1<f32>,
+    weights_trace: Array3<f32>,
+    bias_trace: Array1<f32>,
+    weights_optim: Optimizer<Ix3>,
+    bias_optim: Optimizer<Ix1>,
+}
+
+impl ConvLayer {
+    fn new(opt: &Opt, n_in: usize, n_out: usize, act: Activation) -> Self {
+        let shape = (DIRS.len(), n_in, n_out);
+        let limit = act.init_limit(DIRS.len() * n_in, n_out);
+        let dist = Uniform::new_inclusive(-limit, limit);
+        let mut rng = rng();
+        ConvLayer {
+            weights: Array::from_shape_fn(shape, |_| dist.sample(&mut rng)),
+            bias: Array::zeros(n_out),
+            weights_trace: Array::zeros(shape),
+            bias_trace: Array::zeros(n_out),
+            weights_optim: Optimizer::new(opt, Ix3(DIRS.len(), n_in, n_out)),
+            bias_optim: Optimizer::new(opt, Ix1(n_out)),
+        }
+    }
+
+    /// Pre-activations, given the input for a batch of grids of shape (N * N_CELLS, n_in)
+    fn forward(&self, inp: &ArrayView2<f32>) -> Array2<f32> {
+        let n_grids = inp.rows() / N_CELLS;
+        let mut z = Array::zeros((inp.rows(), self.bias.len()));
+        z.add_assign(&self.bias);
+        for (d, taps) in TAPS.iter().enumerate() {
+            let y = inp.dot(&self.weights.subview(Axis(0), d));
+            for g in 0..n_grids {
+                let o = g * N_CELLS;
+                for &(cell, neigh) in taps {
+                    z.row_mut(o + cell).add_assign(&y.row(o + neigh));
+                }
+            }
+        }
+        z
+    }
+
+    /// Gradients of the weights, bias and input, summed over a batch of grids, given the input
+    /// and the gradient of the pre-activations, both of shape (N * N_CELLS, _)
+    fn gradients(
+        &self,
+        inp: &ArrayView2<f32>,
+        grad_z: &Array2<f32>,
+    ) -> (Array3<f32>, Array1<f32>, Array2<f32>) {
+        let n_grids = inp.rows() / N_CELLS;
+        let mut grad_weights = Array::zeros(self.weights.dim());
+        let mut grad_inp = Array::zeros(inp.dim());
+        for (d, taps) in TAPS.iter().enumerate() {
+            // The input at the neighbor in direction 'd' of each cell; zero if there is none
+            let mut inp_d: Array2<f32> = Array::zeros(inp.dim());
+            for g in 0..n_grids {
+                let o = g * N_CELLS;
+                for &(cell, neigh) in taps {
+                    inp_d.row_mut(o + cell).assign(&inp.row(o + neigh));
+                }
+            }
+            grad_weights
+                .subview_mut(Axis(0), d)
+                .assign(&inp_d.t().dot(grad_z));
+            let grad_inp_d = grad_z.dot(&self.weights.subview(Axis(0), d).t());
+            for g in 0..n_grids {
+                let o = g * N_CELLS;
+                for &(cell, neigh) in taps {
+                    grad_inp
+                        .row_mut(o + neigh)
+                        .add_assign(&grad_inp_d.row(o + cell));
+                }
+            }
+        }
+        (grad_weights, grad_z.sum_axis(Axis(0)), grad_inp)
+    }
+}
+
+/// A convolutional state value network over the hexagonal grid. A stack of convolutions
+/// over adjacent cells is followed by a linear head that is shared between cells; the state
+/// value is the sum of the head outputs of all cells. Since no weights are specific to a cell,
+/// a trained network applies to grids of other sizes. It is trained with semi-gradient
+/// TD(lambda), like the MLP network.
+pub struct HexConv {
+    schedule: Schedule,
+    lambda: f32,
+    activation: Activation,
+    layers: Vec<ConvLayer>,
+    head_weights: Array1<f32>,
+    head_bias: Array1<f32>, // Single element
+    head_weights_trace: Array1<f32>,
+    head_bias_trace: Array1<f32>,
+    head_weights_optim: Optimizer<Ix1>,
+    head_bias_optim: Optimizer<Ix1>,
+}
+
+impl HexConv {
+    /// Return the output of each convolution layer, given the input for a batch of grids
+    fn activations(&self, inp: &ArrayView2<f32>) -> Vec<Array2<f32>> {
+        let mut acts: Vec<Array2<f32>> = Vec::with_capacity(self.layers.len());
+        for layer in &self.layers {
+            let z = match acts.last() {
+                Some(prev) => layer.forward(&prev.view()),
+                None => layer.forward(inp),
+            };
+            acts.push(self.activation.apply(z));
+        }
+        acts
+    }
+
+    /// Per-cell head outputs, given the output of the last convolution layer (or the input)
+    fn head(&self, last: &ArrayView2<f32>) -> Array1<f32> {
+        last.dot(&self.head_weights) + self.head_bias[[0]]
+    }
+}
+
+impl Net for HexConv {
+    fn new(opt: &Opt) -> Self {
+        let mut sizes = vec![CHANNELS + 1];
+        sizes.extend(opt.conv_filters.iter());
+        let layers = sizes
+            .windows(2)
+            .map(|w| ConvLayer::new(opt, w[0], w[1], opt.activation))
+            .collect();
+        let n_head = sizes[sizes.len() - 1];
+        HexConv {
+            schedule: Schedule::new(opt),
+            lambda: opt.lambda.unwrap_or(0.0),
+            activation: opt.activation,
+            layers,
+            // Zero weights so that all states are initially valued equally
+            head_weights: Array::zeros(n_head),
+            // The value of a state sums the head bias over the cells
+            head_bias: Array::from_elem(1, opt.optimistic_init.unwrap_or(0.0) / N_CELLS as f32),
+            head_weights_trace: Array::zeros(n_head),
+            head_bias_trace: Array::zeros(1),
+            head_weights_optim: Optimizer::new(opt, Ix1(n_head)),
+            head_bias_optim: Optimizer::new(opt, Ix1(1)),
+        }
+    }
+
+    /// Forward pass. Calculate the state value of one (3D array) or multiple (4D) freps.
+    fn forward<S, D>(&mut self, freps: &ArrayBase<S, D>) -> Array1<f32>
+    where
+        S: Data<Elem = u8>,
+        D: Dimension,
+    {
+        let n_freps = if freps.ndim() == 3 {
+            1
+        } else {
+            freps.len_of(Axis(0))
+        };
+        let inp = net_input(freps, (n_freps * N_CELLS, CHANNELS + 1));
+        let cell_vals = match self.activations(&inp.view()).pop() {
+            Some(last) => self.head(&last.view()),
+            None => self.head(&inp.view()),
+        };
+        cell_vals
+            .into_shape((n_freps, N_CELLS))
+            .expect("Cell vals reshape fail")
+            .sum_axis(Axis(1))
+    }
+
+    /// Backward pass. Semi-gradient TD(lambda) update with backpropagated gradients.
+    fn backward<S: Data<Elem = u8>>(
+        &mut self,
+        frep: &Frep<S>,
+        reward: f32,
+        avg_reward: f32,
+        discount: f32,
+        next_frep: &Frep<S>,
+    ) -> f32 {
+        let inp = net_input(frep, (N_CELLS, CHANNELS + 1));
+        let acts = self.activations(&inp.view());
+        let (value, grad_head_weights) = {
+            let last = match acts.last() {
+                Some(last) => last.view(),
+                None => inp.view(),
+            };
+            (self.head(&last).scalar_sum(), last.sum_axis(Axis(0)))
+        };
+        let next_value = self.forward(next_frep)[[0]];
+        let td_err = reward - avg_reward + discount * next_value - value;
+        let lr = self.schedule.next_lr();
+
+        // The gradient of the state value w.r.t. the output of each cell of the last
+        // convolution layer equals the head weights
+        let mut grad_act = Array::from_shape_fn((N_CELLS, self.head_weights.len()), |(_, f)| {
+            self.head_weights[[f]]
+        });
+        for i in (0..self.layers.len()).rev() {
+            let grad_z = grad_act * self.activation.derivative(&acts[i]);
+            let layer_inp = match i {
+                0 => inp.view(),
+                _ => acts[i - 1].view(),
+            };
+            let (grad_weights, grad_bias, grad_inp) = self.layers[i].gradients(&layer_inp, &grad_z);
+            grad_act = grad_inp;
+            let layer = &mut self.layers[i];
+            layer.weights_trace.mul_assign(discount * self.lambda);
+            layer.weights_trace.add_assign(&grad_weights);
+            layer.bias_trace.mul_assign(discount * self.lambda);
+            layer.bias_trace.add_assign(&grad_bias);
+            layer
+                .weights_optim
+                .step(&mut layer.weights, &(td_err * &layer.weights_trace), lr);
+            layer
+                .bias_optim
+                .step(&mut layer.bias, &(td_err * &layer.bias_trace), lr);
+        }
+        self.head_weights_trace.mul_assign(discount * self.lambda);
+        self.head_weights_trace.add_assign(&grad_head_weights);
+        self.head_bias_trace.mul_assign(discount * self.lambda);
+        self.head_bias_trace.add_assign(N_CELLS as f32);
+        self.head_weights_optim.step(
+            &mut self.head_weights,
+            &(td_err * &self.head_weights_trace),
+            lr,
+        );
+        self.head_bias_optim
+            .step(&mut self.head_bias, &(td_err * &self.head_bias_trace), lr);
+        td_err
+    }
+
+    fn backward_batch<S: Data<Elem = u8>>(
+        &mut self,
+        freps: &ArrayBase<S, Ix4>,
+        rewards: &Array1<f32>,
+        avg_rewards: &Array1<f32>,
+        discounts: &Array1<f32>,
+        next_freps: &ArrayBase<S, Ix4>,
+        weights: &Array1<f32>,
+    ) -> Array1<f32> {
+        let n = freps.len_of(Axis(0));
+        let inp = net_input(freps, (n * N_CELLS, CHANNELS + 1));
+        let acts = self.activations(&inp.view());
+        let (values, last_sums) = {
+            let last = match acts.last() {
+                Some(last) => last.view(),
+                None => inp.view(),
+            };
+            let values = self
+                .head(&last)
+                .into_shape((n, N_CELLS))
+                .expect("Cell vals reshape fail")
+                .sum_axis(Axis(1));
+            // Sum over cells of the output of the last convolution layer, for each grid
+            let last_sums = last
+                .into_shape((n, N_CELLS, last.cols()))
+                .expect("Last act reshape fail")
+                .sum_axis(Axis(1));
+            (values, last_sums)
+        };
+        let next_values = self.forward(next_freps);
+        let td_errs = batch_td_errs(&values, &next_values, rewards, avg_rewards, discounts);
+        let lr = self.schedule.next_lr();
+        // Each update is the weighted mean over the batch of the TD error times the gradient
+        let coefs = (&td_errs * weights).mapv(|td_err| td_err / n as f32);
+
+        // The gradient w.r.t. the output of each cell of the last convolution layer
+        // equals the head weights, for each grid
+        let mut grad_act =
+            Array::from_shape_fn((n * N_CELLS, self.head_weights.len()), |(i, f)| {
+                coefs[[i / N_CELLS]] * self.head_weights[[f]]
+            });
+        for i in (0..self.layers.len()).rev() {
+            let grad_z = grad_act * self.activation.derivative(&acts[i]);
+            let layer_inp = match i {
+                0 => inp.view(),
+                _ => acts[i - 1].view(),
+            };
+            let (upd_weights, upd_bias, grad_inp) = self.layers[i].gradients(&layer_inp, &grad_z);
+            grad_act = grad_inp;
+            let layer = &mut self.layers[i];
+            layer
+                .weights_optim
+                .step(&mut layer.weights, &upd_weights, lr);
+            layer.bias_optim.step(&mut layer.bias, &upd_bias, lr);
+        }
+        let upd_head_weights = last_sums
+            .t()
+            .dot(&coefs.view().into_shape((n, 1)).expect("Coefs reshape"))
+            .into_shape(self.head_weights.len())
+            .expect("Head update reshape");
+        self.head_weights_optim
+            .step(&mut self.head_weights, &upd_head_weights, lr);
+        let upd_head_bias = Array::from_elem(1, coefs.scalar_sum() * N_CELLS as f32);
+        self.head_bias_optim
+            .step(&mut self.head_bias, &upd_head_bias, lr);
+        td_errs
+    }
+
+    /// Load the parameters of each convolution layer from '<stem>_<layer>_weights.npy' and
+    /// '<stem>_<layer>_bias.npy' files, and those of the head from '<stem>_head_weights.npy'
+    /// and '<stem>_head_bias.npy' files, next to 'path'
+    fn load(&mut self, path: &Path) -> io::Result<()> {
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            let shape = layer.weights.shape().to_vec();
+            layer.weights = read_npy_shaped(&param_path(path, i, "weights"), &shape)?
+                .into_dimensionality::<Ix3>()
+                .expect("Shape checked on read");
+            layer.bias = read_npy_shaped(&param_path(path, i, "bias"), &[layer.bias.len()])?
+                .into_dimensionality::<Ix1>()
+                .expect("Shape checked on read");
+        }
+        let n_head = self.head_weights.len();
+        self.head_weights = read_npy_shaped(&param_path(path, "head", "weights"), &[n_head])?
+            .into_dimensionality::<Ix1>()
+            .expect("Shape checked on read");
+        self.head_bias = read_npy_shaped(&param_path(path, "head", "bias"), &[1])?
+            .into_dimensionality::<Ix1>()
+            .expect("Shape checked on read");
+        Ok(())
+    }
+
+    fn save(&self, path: &Path) -> io::Result<()> {
+        for (i, layer) in self.layers.iter().enumerate() {
+            write_npy(param_path(path, i, "weights"), &layer.weights)?;
+            write_npy(param_path(path, i, "bias"), &layer.bias)?;
+        }
+        write_npy(param_path(path, "head", "weights"), &self.head_weights)?;
+        write_npy(param_path(path, "head", "bias"), &self.head_bias)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gridfuncs::*;
+    use hexconv::*;
+    use ndarray::stack;
+    use structopt::StructOpt;
+
+    fn freps() -> (FrepO, FrepO) {
+        let mut grid = BitGrid::default();
+        grid.set(2, 3, 5, true);
+        grid.set(0, 6, 5, true);
+        let frep = feature_rep(&grid);
+        grid.set(5, 1, 7, true);
+        (frep, feature_rep(&grid))
+    }
+
+    #[test]
+    fn test_taps() {
+        // Every cell is its own neighbor in direction (0, 0)
+        assert_eq!(TAPS[0], (0..N_CELLS).map(|i| (i, i)).collect::<Vec<_>>());
+        for r in 0..ROWS {
+            for c in 0..COLS {
+                let n_taps = TAPS
+                    .iter()
+                    .flat_map(|taps| taps.iter())
+                    .filter(|&&(cell, _)| cell == r * COLS + c)
+                    .count();
+                assert_eq!(n_taps, neighbors(1, r, c, true).rows());
+            }
+        }
+    }
+
+    #[test]
+    fn test_forward_batch() {
+        let opt = Opt::from_iter(&["DCA", "--conv_filters", "4,3", "--activation", "tanh"]);
+        let mut net = HexConv::new(&opt);
+        net.head_weights.fill(0.5);
+        let (frep, next_frep) = freps();
+        let freps = stack(
+            Axis(0),
+            &[
+                frep.view().insert_axis(Axis(0)),
+                next_frep.view().insert_axis(Axis(0)),
+            ],
+        )
+        .unwrap();
+        let vals = net.forward(&freps);
+        assert_eq!(vals.shape(), &[2]);
+        assert!((vals[[0]] - net.forward(&frep)[[0]]).abs() < 1e-4);
+        assert!((vals[[1]] - net.forward(&next_frep)[[0]]).abs() < 1e-4);
+    }
+
+    #[test]
+    /// A TD update should move the state value towards the TD target
+    fn test_backward() {
+        let opt = Opt::from_iter(&["DCA", "--conv_filters", "4", "--alpha", "1e-5"]);
+        let mut net = HexConv::new(&opt);
+        let (frep, next_frep) = freps();
+        // Non-zero head weights so that gradients reach the convolution layer
+        net.head_weights.fill(0.1);
+        let weights = net.layers[0].weights.clone();
+        let value = net.forward(&frep)[[0]];
+        let td_err = net.backward(&frep, 10.0, 0.0, 1.0, &next_frep);
+        assert!(td_err > 0.0);
+        assert!(net.forward(&frep)[[0]] > value);
+        assert!(net.layers[0].weights != weights);
+    }
+
+    #[test]
+    /// A batch of one transition should give the one-step update
+    fn test_backward_batch() {
+        let opt = Opt::from_iter(&[
+            "DCA",
+            "--conv_filters",
+            "3,2",
+            "--activation",
+            "tanh",
+            "--alpha",
+            "1e-4",
+        ]);
+        let mut net1 = HexConv::new(&opt);
+        net1.head_weights.fill(0.3);
+        let mut net2 = HexConv::new(&opt);
+        net2.head_weights.fill(0.3);
+        for (l1, l2) in net1.layers.iter().zip(net2.layers.iter_mut()) {
+            l2.weights.assign(&l1.weights);
+        }
+        let weights = net1.layers[0].weights.clone();
+        let (frep, next_frep) = freps();
+        let err1 = net1.backward(&frep, 1.0, 0.5, 0.9, &next_frep);
+        let errs2 = net2.backward_batch(
+            &frep.view().insert_axis(Axis(0)),
+            &array![1.0],
+            &array![0.5],
+            &array![0.9],
+            &next_frep.view().insert_axis(Axis(0)),
+            &array![1.0],
+        );
+        assert!((err1 - errs2[[0]]).abs() < 1e-3);
+        assert!(!net2.layers[0].weights.all_close(&weights, 1e-5));
+        for (l1, l2) in net1.layers.iter().zip(&net2.layers) {
+            assert!(l1.weights.all_close(&l2.weights, 1e-5));
+            assert!(l1.bias.all_close(&l2.bias, 1e-5));
+        }
+        assert!(net1.head_weights.all_close(&net2.head_weights, 1e-5));
+        assert!(net1.head_bias.all_close(&net2.head_bias, 1e-5));
+    }
+
+    #[test]
+    /// Backpropagated gradients should match finite differences of the forward pass
+    fn test_gradients() {
+        let opt = Opt::from_iter(&["DCA", "--conv_filters", "3,2", "--activation", "tanh"]);
+        let mut net = HexConv::new(&opt);
+        net.head_weights.fill(0.3);
+        let (frep, next_frep) = freps();
+        // Keep the pre-activations small, as for inputs scaled down by the number of channels
+        net.layers[0].weights.mapv_inplace(|w| w / 70.0);
+        let params: Vec<_> = net
+            .layers
+            .iter()
+            .map(|l| (l.weights.clone(), l.bias.clone()))
+            .collect();
+        let (head_weights, head_bias) = (net.head_weights.clone(), net.head_bias.clone());
+        let alpha = 1e-3;
+        net.schedule = Schedule::new(&Opt::from_iter(&["DCA", "--alpha", "1e-3"]));
+        let td_err = net.backward(&frep, 1.0, 0.0, 1.0, &next_frep);
+        // With lambda = 0, the update is 'alpha * td_err * grad'
+        let weights = params[0].0.clone();
+        let grads = (&net.layers[0].weights - &weights) / (alpha * td_err);
+        // Restore the parameters of the network before the update
+        for (layer, (w, b)) in net.layers.iter_mut().zip(params) {
+            layer.weights = w;
+            layer.bias = b;
+        }
+        net.head_weights = head_weights;
+        net.head_bias = head_bias;
+        let eps = 1e-2 / 70.0;
+        for &idx in &[(0, 5, 0), (3, 70, 2), (6, 20, 1)] {
+            net.layers[0].weights[idx] = weights[idx] + eps;
+            let val_plus = net.forward(&frep)[[0]];
+            net.layers[0].weights[idx] = weights[idx] - eps;
+            let val_minus = net.forward(&frep)[[0]];
+            net.layers[0].weights[idx] = weights[idx];
+            let fd_grad = (val_plus - val_minus) / (2.0 * eps);
+            assert!(
+                (fd_grad - grads[idx]).abs() < 1e-2 * fd_grad.abs().max(1e-1),
+                "{:?}: {} vs {}",
+                idx,
+                fd_grad,
+                grads[idx]
+            );
+        }
+    }
+}
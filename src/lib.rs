@@ -0,0 +1,371 @@
+pub mod agent;
+pub mod environment;
+pub mod error;
+pub mod eventgen;
+pub mod exploration;
+pub mod gridfuncs;
+pub mod hexconv;
+pub mod mlp;
+pub mod npy;
+pub mod optim;
+pub mod random;
+pub mod replay;
+pub mod reward;
+pub mod stats;
+pub mod tabular;
+pub mod vecenv;
+pub mod vnet_agent;
+
+#[cfg(feature = "python")]
+mod python;
+
+extern crate ctrlc;
+#[macro_use]
+extern crate ndarray;
+#[macro_use]
+extern crate lazy_static;
+extern crate ordered_float;
+extern crate rand;
+#[macro_use]
+extern crate structopt;
+#[macro_use]
+extern crate clap;
+extern crate chrono;
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate itertools;
+// pyo3's generated code refers to '::core', which 2015-edition crates must declare
+#[cfg(feature = "python")]
+extern crate core;
+#[cfg(feature = "python")]
+extern crate numpy;
+#[cfg(feature = "python")]
+extern crate pyo3;
+
+use agent::AgentKind;
+use environment::IllegalActionPolicy;
+use exploration::ExplorationKind;
+use mlp::Activation;
+use optim::{LrSchedule, OptimizerKind};
+use replay::ReplayKind;
+use reward::RewardKind;
+use vnet_agent::{NetKind, UpdateRule};
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "DCA")]
+pub struct Opt {
+    /// Call duration, in minutes
+    #[structopt(long = "call_dur", default_value = "3")]
+    pub call_dur: f32,
+
+    /// Call duration for hand-offs, in minutes
+    #[structopt(long = "hoff_call_dur", default_value = "1")]
+    pub hoff_call_dur: f32,
+
+    /// Call rate, in calls per hour
+    #[structopt(short = "r", long = "call_rate", default_value = "200")]
+    pub call_rate_ph: f32,
+
+    /// Hand-off probability
+    #[structopt(short = "phoff", long = "p_handoff", default_value = "0.0")]
+    pub p_hoff: f32,
+
+    /// Simulation duration
+    #[structopt(short = "i", long = "n_events", default_value = "100000")]
+    pub n_events: i32,
+
+    /// Seed for the random number generators, for reproducible simulations. Seeded from
+    /// the OS if not given
+    #[structopt(long = "seed")]
+    pub seed: Option<u64>,
+
+    /// Show blocking probability every 'log_iter' iterations
+    #[structopt(long = "log_iter", default_value = "10000")]
+    pub log_iter: i32,
+
+    /// Learning rate for neural network
+    #[structopt(short = "l", long = "alpha", default_value = "2.52e-6")]
+    pub alpha: f32,
+
+    /// Learning rate for average reward
+    #[structopt(short = "a", long = "alpha_avg", default_value = "0.06")]
+    pub alpha_avg: f32,
+
+    /// Learning rate for TDC gradient corrections
+    #[structopt(short = "g", long = "alpha_grad", default_value = "5e-6")]
+    pub alpha_grad: f32,
+
+    /// Optimizer for the network weights
+    #[structopt(
+        long = "optimizer",
+        default_value = "SGD",
+        raw(
+            possible_values = "&OptimizerKind::variants()",
+            case_insensitive = "true"
+        )
+    )]
+    pub optimizer: OptimizerKind,
+
+    /// Decay rate of the first moment (momentum) for the Momentum and Adam optimizers
+    #[structopt(long = "beta1", default_value = "0.9")]
+    pub beta1: f32,
+
+    /// Decay rate of the second moment for the RMSProp and Adam optimizers
+    #[structopt(long = "beta2", default_value = "0.999")]
+    pub beta2: f32,
+
+    /// Term added to the denominator of the RMSProp and Adam optimizers for numerical stability
+    #[structopt(long = "optim_eps", default_value = "1e-8")]
+    pub optim_eps: f32,
+
+    /// Learning rate schedule for the network weights
+    #[structopt(
+        long = "lr_schedule",
+        default_value = "Constant",
+        raw(possible_values = "&LrSchedule::variants()", case_insensitive = "true")
+    )]
+    pub lr_schedule: LrSchedule,
+
+    /// Decay rate of the learning rate schedule
+    #[structopt(long = "lr_decay", default_value = "0.5")]
+    pub lr_decay: f32,
+
+    /// Number of updates over which the learning rate decays by 'lr_decay'
+    #[structopt(
+        long = "lr_decay_steps",
+        default_value = "100000",
+        raw(validator = "nonzero")
+    )]
+    pub lr_decay_steps: u64,
+
+    /// Agent
+    #[structopt(
+        long = "agent",
+        default_value = "AAVNet",
+        raw(possible_values = "&AgentKind::variants()", case_insensitive = "true")
+    )]
+    pub agent: AgentKind,
+
+    /// Learning rate for the q-values of tabular agents
+    #[structopt(long = "alpha_table", default_value = "0.05")]
+    pub alpha_table: f32,
+
+    /// Discount factor for the discounted return criterion. The average reward criterion
+    /// is used if not given
+    #[structopt(long = "gamma")]
+    pub gamma: Option<f32>,
+
+    /// Discount by 'gamma' per minute between events instead of per event, for the
+    /// discounted return criterion
+    #[structopt(long = "semi_markov")]
+    pub semi_markov: bool,
+
+    /// Continuous-time rewards: the average reward is estimated per minute, and the reward
+    /// defaults to the number of channels in use integrated over the time between events
+    #[structopt(long = "continuous_time")]
+    pub continuous_time: bool,
+
+    /// Reward function. Defaults to 'TimeIntegrated' with '--continuous_time' and
+    /// 'ChannelsInUse' otherwise
+    #[structopt(
+        long = "reward",
+        raw(possible_values = "&RewardKind::variants()", case_insensitive = "true")
+    )]
+    pub reward: Option<RewardKind>,
+
+    /// Penalty for a dropped hand-off, relative to a blocked new call, for the 'HoffDropPenalty' reward
+    #[structopt(long = "hoff_weight", default_value = "5")]
+    pub hoff_weight: f32,
+
+    /// Weight of the deviations of per-cell channel usage for the 'Fairness' reward
+    #[structopt(long = "fairness_weight", default_value = "0.1")]
+    pub fairness_weight: f32,
+
+    /// State value network
+    #[structopt(
+        long = "net",
+        default_value = "VNet",
+        raw(possible_values = "&NetKind::variants()", case_insensitive = "true")
+    )]
+    pub net: NetKind,
+
+    /// Sizes of the hidden layers of the MLP network, e.g. '--hidden 100,50'
+    #[structopt(long = "hidden", default_value = "100", raw(use_delimiter = "true"))]
+    pub hidden: Vec<usize>,
+
+    /// Number of filters of each convolution layer of the HexConv network, e.g. '--conv_filters 16,8'
+    #[structopt(
+        long = "conv_filters",
+        default_value = "16",
+        raw(use_delimiter = "true")
+    )]
+    pub conv_filters: Vec<usize>,
+
+    /// Activation function for the hidden layers of the MLP and HexConv networks
+    #[structopt(
+        long = "activation",
+        default_value = "ReLU",
+        raw(possible_values = "&Activation::variants()", case_insensitive = "true")
+    )]
+    pub activation: Activation,
+
+    /// Update rule for the linear value network weights
+    #[structopt(
+        long = "update_rule",
+        default_value = "TDCVariant",
+        raw(possible_values = "&UpdateRule::variants()", case_insensitive = "true")
+    )]
+    pub update_rule: UpdateRule,
+
+    /// Action selection policy
+    #[structopt(
+        long = "exploration",
+        default_value = "Greedy",
+        raw(
+            possible_values = "&ExplorationKind::variants()",
+            case_insensitive = "true"
+        )
+    )]
+    pub exploration: ExplorationKind,
+
+    /// Initial probability of a random action for epsilon-greedy exploration
+    #[structopt(long = "epsilon", default_value = "0.1")]
+    pub epsilon: f32,
+
+    /// Decay factor of 'epsilon' per action selection
+    #[structopt(long = "epsilon_decay", default_value = "0.99999")]
+    pub epsilon_decay: f32,
+
+    /// Initial temperature for Boltzmann exploration
+    #[structopt(long = "temp", default_value = "1.0")]
+    pub temp: f32,
+
+    /// Decay factor of the temperature per action selection
+    #[structopt(long = "temp_decay", default_value = "0.99999")]
+    pub temp_decay: f32,
+
+    /// Optimistic initial estimate of the average reward, q-values and state values. Until the
+    /// estimates have converged, TD errors are negative and the values of visited afterstates
+    /// decrease, which drives exploration towards afterstates that have not been visited
+    #[structopt(long = "optimistic_init")]
+    pub optimistic_init: Option<f32>,
+
+    /// Decay rate of the follow-on trace for emphatic TD
+    #[structopt(long = "followon_decay", default_value = "0.9")]
+    pub followon_decay: f32,
+
+    /// Decay rate for eligibility traces, TDC(lambda). One-step TDC if not given
+    #[structopt(long = "lambda")]
+    pub lambda: Option<f32>,
+
+    /// Initialize network weights from a '.npy' file, e.g. as trained by the Python implementation
+    #[structopt(long = "load_weights")]
+    pub load_weights: Option<String>,
+
+    /// Save network weights to a '.npy' file at the end of the simulation
+    #[structopt(long = "save_weights")]
+    pub save_weights: Option<String>,
+
+    /// Also load and save the TDC gradient correction weights and eligibility traces of the
+    /// linear network, e.g. as 'vnet_0_grad_corr.npy' and 'vnet_0_traces.npy' for 'vnet.npy'
+    #[structopt(long = "with_traces")]
+    pub with_traces: bool,
+
+    /// Train on mini-batches sampled from an experience replay buffer instead of the last transition
+    #[structopt(
+        long = "replay",
+        raw(possible_values = "&ReplayKind::variants()", case_insensitive = "true")
+    )]
+    pub replay: Option<ReplayKind>,
+
+    /// Capacity of the experience replay buffer
+    #[structopt(
+        long = "replay_size",
+        default_value = "1000",
+        raw(validator = "nonzero")
+    )]
+    pub replay_size: usize,
+
+    /// Number of transitions per mini-batch
+    #[structopt(long = "batch_size", default_value = "32", raw(validator = "nonzero"))]
+    pub batch_size: usize,
+
+    /// Exponent of the absolute TD error for the priority of a transition in prioritized replay
+    #[structopt(long = "priority_exp", default_value = "0.6")]
+    pub priority_exp: f32,
+
+    /// Exponent of the importance-sampling weights that correct for the bias of prioritized
+    /// replay; 0 for no correction and 1 for full correction
+    #[structopt(long = "priority_is_exp", default_value = "0.4")]
+    pub priority_is_exp: f32,
+
+    /// Number of environments simulated in parallel with a shared agent
+    #[structopt(long = "n_envs", default_value = "1")]
+    pub n_envs: usize,
+
+    /// Step each of the parallel environments on its own thread
+    #[structopt(long = "threaded")]
+    pub threaded: bool,
+
+    /// Verify channel reuse constraint each iteration
+    #[structopt(long = "verify_grid")]
+    pub verify_grid: bool,
+
+    /// On an accepted call arrival, move another ongoing call in the cell to the eligible
+    /// channel that is in use in the most cells nearby, if that packs channels more compactly
+    #[structopt(long = "arrival_repack")]
+    pub arrival_repack: bool,
+
+    /// Response to an action that is not legal for the event
+    #[structopt(
+        long = "illegal_action",
+        default_value = "Error",
+        raw(
+            possible_values = "&IllegalActionPolicy::variants()",
+            case_insensitive = "true"
+        )
+    )]
+    pub illegal_action: IllegalActionPolicy,
+
+    /// Verify the incrementally updated feature representation of the environment, and the
+    /// afterstate feature representation of the agent, against one computed from scratch
+    /// each iteration
+    #[structopt(long = "verify_frep")]
+    pub verify_frep: bool,
+
+    /// Log level: '-v' for debug, '-vv' for trace
+    #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
+    pub verbose: u8,
+}
+
+/// Validate that an argument is a positive integer
+fn nonzero(arg: String) -> Result<(), String> {
+    match arg.parse::<u64>() {
+        Ok(0) => Err("must be greater than 0".to_string()),
+        Ok(_) => Ok(()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+impl Opt {
+    /// Reject combinations of options that are not supported together
+    pub fn validate(&self) -> Result<(), clap::Error> {
+        let conflict = |msg: &str| {
+            Err(clap::Error::with_description(
+                msg,
+                clap::ErrorKind::ArgumentConflict,
+            ))
+        };
+        if self.lambda.is_some() && self.update_rule == UpdateRule::GTD2 {
+            return conflict("GTD2 does not support eligibility traces ('--lambda')");
+        }
+        if self.lambda.is_some() && (self.replay.is_some() || self.n_envs > 1) {
+            return conflict(
+                "Experience replay and parallel environments do not support \
+                 eligibility traces ('--lambda')",
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,350 @@
+use gridfuncs::Frep;
+use ndarray::{Array, Array1, Array2, ArrayBase, ArrayView2, Axis, Data, Dimension, Ix1, Ix2, Ix4};
+use npy::{param_path, read_npy_shaped, write_npy};
+use optim::{Optimizer, Schedule};
+use rand::distributions::{Distribution, Uniform};
+use random::rng;
+use std::io;
+use std::ops::{AddAssign, MulAssign};
+use std::path::Path;
+use vnet_agent::{batch_td_errs, net_input, Net, WDIM};
+use Opt;
+
+arg_enum! {
+    /// Activation functions for the hidden layers of the MLP
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Activation {
+        ReLU,
+        Tanh
+    }
+}
+
+impl Activation {
+    pub fn apply(self, z: Array2<f32>) -> Array2<f32> {
+        match self {
+            Activation::ReLU => z.mapv(|x| x.max(0.0)),
+            Activation::Tanh => z.mapv(f32::tanh),
+        }
+    }
+
+    /// Derivative of the activation function, given its output
+    pub fn derivative(self, act: &Array2<f32>) -> Array2<f32> {
+        match self {
+            Activation::ReLU => act.mapv(|x| if x > 0.0 { 1.0 } else { 0.0 }),
+            Activation::Tanh => act.mapv(|x| 1.0 - x * x),
+        }
+    }
+
+    /// Weights are initialized uniformly within [-limit, limit];
+    /// He initialization for ReLU and Glorot initialization for tanh
+    pub fn init_limit(self, fan_in: usize, fan_out: usize) -> f32 {
+        match self {
+            Activation::ReLU => (6.0 / fan_in as f32).sqrt(),
+            Activation::Tanh => (6.0 / (fan_in + fan_out) as f32).sqrt(),
+        }
+    }
+}
+
+struct Layer {
+    weights: Array2<f32>, // (n_in, n_out)
+    bias: Array1<f32>,
+    weights_trace: Array2<f32>,
+    bias_trace: Array1<f32>,
+    weights_optim: Optimizer<Ix2>,
+    bias_optim: Optimizer<Ix1>,
+}
+
+impl Layer {
+    /// Uniformly initialized weights, or zero weights if the layer is the
+    /// output layer so that all states are initially valued equally.
+    fn new(opt: &Opt, n_in: usize, n_out: usize, activation: Option<Activation>) -> Self {
+        let weights = match activation {
+            Some(act) => {
+                let limit = act.init_limit(n_in, n_out);
+                let dist = Uniform::new_inclusive(-limit, limit);
+                let mut rng = rng();
+                Array::from_shape_fn((n_in, n_out), |_| dist.sample(&mut rng))
+            }
+            None => Array::zeros((n_in, n_out)),
+        };
+        Layer {
+            weights,
+            bias: Array::zeros(n_out),
+            weights_trace: Array::zeros((n_in, n_out)),
+            bias_trace: Array::zeros(n_out),
+            weights_optim: Optimizer::new(opt, Ix2(n_in, n_out)),
+            bias_optim: Optimizer::new(opt, Ix1(n_out)),
+        }
+    }
+}
+
+/// A multi-layer perceptron state value network with fully connected hidden layers
+/// and a linear output layer. It is trained with semi-gradient TD(lambda),
+/// with backpropagated gradients of the state value. The weight update rule
+/// of the linear network ('--update_rule') does not apply.
+pub struct MLP {
+    schedule: Schedule,
+    lambda: f32,
+    activation: Activation,
+    layers: Vec<Layer>,
+}
+
+impl MLP {
+    /// Return the output of each layer for a batch of flattened freps of shape (N, WDIM)
+    fn activations(&self, inp: &ArrayView2<f32>) -> Vec<Array2<f32>> {
+        let mut acts: Vec<Array2<f32>> = Vec::with_capacity(self.layers.len());
+        for (i, layer) in self.layers.iter().enumerate() {
+            let z = match acts.last() {
+                Some(prev) => prev.dot(&layer.weights) + &layer.bias,
+                None => inp.dot(&layer.weights) + &layer.bias,
+            };
+            let act = if i + 1 < self.layers.len() {
+                self.activation.apply(z)
+            } else {
+                z
+            };
+            acts.push(act);
+        }
+        acts
+    }
+}
+
+impl Net for MLP {
+    fn new(opt: &Opt) -> Self {
+        let mut sizes = vec![WDIM];
+        sizes.extend(opt.hidden.iter());
+        sizes.push(1);
+        let n_layers = sizes.len() - 1;
+        let mut layers: Vec<Layer> = (0..n_layers)
+            .map(|i| {
+                let activation = if i + 1 < n_layers {
+                    Some(opt.activation)
+                } else {
+                    None
+                };
+                Layer::new(opt, sizes[i], sizes[i + 1], activation)
+            })
+            .collect();
+        // The output bias offsets the value of every state
+        layers[n_layers - 1]
+            .bias
+            .fill(opt.optimistic_init.unwrap_or(0.0));
+        MLP {
+            schedule: Schedule::new(opt),
+            lambda: opt.lambda.unwrap_or(0.0),
+            activation: opt.activation,
+            layers,
+        }
+    }
+
+    /// Forward pass. Calculate the state value of one (3D array) or multiple (4D) freps.
+    fn forward<S, D>(&mut self, freps: &ArrayBase<S, D>) -> Array1<f32>
+    where
+        S: Data<Elem = u8>,
+        D: Dimension,
+    {
+        let n_freps = if freps.ndim() == 3 {
+            1
+        } else {
+            freps.len_of(Axis(0))
+        };
+        let inp = net_input(freps, (n_freps, WDIM));
+        self.activations(&inp.view())
+            .pop()
+            .expect("MLP without layers")
+            .into_shape(n_freps)
+            .expect("State val flatten fail")
+    }
+
+    /// Backward pass. Semi-gradient TD(lambda) update with backpropagated gradients.
+    fn backward<S: Data<Elem = u8>>(
+        &mut self,
+        frep: &Frep<S>,
+        reward: f32,
+        avg_reward: f32,
+        discount: f32,
+        next_frep: &Frep<S>,
+    ) -> f32 {
+        let inp = net_input(frep, (1, WDIM));
+        let acts = self.activations(&inp.view());
+        let value = acts[acts.len() - 1][[0, 0]];
+        let next_value = self.forward(next_frep)[[0]];
+        let td_err = reward - avg_reward + discount * next_value - value;
+        let lr = self.schedule.next_lr();
+
+        // Backpropagate the gradient of the state value, starting at the output where
+        // d(value)/d(output) = 1. 'delta' is the gradient w.r.t. the pre-activations of layer 'i'.
+        let mut delta: Array2<f32> = Array::ones((1, 1));
+        for i in (0..self.layers.len()).rev() {
+            let grad_weights = match i {
+                0 => inp.t().dot(&delta),
+                _ => acts[i - 1].t().dot(&delta),
+            };
+            let grad_bias = delta.subview(Axis(0), 0).to_owned();
+            if i > 0 {
+                // Must be computed before the weights of this layer are updated
+                delta = delta.dot(&self.layers[i].weights.t())
+                    * self.activation.derivative(&acts[i - 1]);
+            }
+            let layer = &mut self.layers[i];
+            layer.weights_trace.mul_assign(discount * self.lambda);
+            layer.weights_trace.add_assign(&grad_weights);
+            layer.bias_trace.mul_assign(discount * self.lambda);
+            layer.bias_trace.add_assign(&grad_bias);
+            layer
+                .weights_optim
+                .step(&mut layer.weights, &(td_err * &layer.weights_trace), lr);
+            layer
+                .bias_optim
+                .step(&mut layer.bias, &(td_err * &layer.bias_trace), lr);
+        }
+        td_err
+    }
+
+    fn backward_batch<S: Data<Elem = u8>>(
+        &mut self,
+        freps: &ArrayBase<S, Ix4>,
+        rewards: &Array1<f32>,
+        avg_rewards: &Array1<f32>,
+        discounts: &Array1<f32>,
+        next_freps: &ArrayBase<S, Ix4>,
+        weights: &Array1<f32>,
+    ) -> Array1<f32> {
+        let n = freps.len_of(Axis(0));
+        let inp = net_input(freps, (n, WDIM));
+        let acts = self.activations(&inp.view());
+        let values = acts[acts.len() - 1]
+            .view()
+            .into_shape(n)
+            .expect("State val flatten fail")
+            .to_owned();
+        let next_values = self.forward(next_freps);
+        let td_errs = batch_td_errs(&values, &next_values, rewards, avg_rewards, discounts);
+        let lr = self.schedule.next_lr();
+
+        // Backpropagate the weighted mean over the batch of the TD error times the gradient of
+        // the state value. 'delta' is the gradient w.r.t. the pre-activations of layer 'i'.
+        let mut delta: Array2<f32> = (&td_errs * weights)
+            .mapv(|td_err| td_err / n as f32)
+            .into_shape((n, 1))
+            .expect("TD errs reshape");
+        for i in (0..self.layers.len()).rev() {
+            let upd_weights = match i {
+                0 => inp.t().dot(&delta),
+                _ => acts[i - 1].t().dot(&delta),
+            };
+            let upd_bias = delta.sum_axis(Axis(0));
+            if i > 0 {
+                delta = delta.dot(&self.layers[i].weights.t())
+                    * self.activation.derivative(&acts[i - 1]);
+            }
+            let layer = &mut self.layers[i];
+            layer
+                .weights_optim
+                .step(&mut layer.weights, &upd_weights, lr);
+            layer.bias_optim.step(&mut layer.bias, &upd_bias, lr);
+        }
+        td_errs
+    }
+
+    /// Load the weights and biases of each layer from '<stem>_<layer>_weights.npy' and
+    /// '<stem>_<layer>_bias.npy' files next to 'path'
+    fn load(&mut self, path: &Path) -> io::Result<()> {
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            let shape = layer.weights.shape().to_vec();
+            layer.weights = read_npy_shaped(&param_path(path, i, "weights"), &shape)?
+                .into_dimensionality::<Ix2>()
+                .expect("Shape checked on read");
+            layer.bias = read_npy_shaped(&param_path(path, i, "bias"), &[layer.bias.len()])?
+                .into_shape(layer.bias.len())
+                .expect("Shape checked on read");
+        }
+        Ok(())
+    }
+
+    fn save(&self, path: &Path) -> io::Result<()> {
+        for (i, layer) in self.layers.iter().enumerate() {
+            write_npy(param_path(path, i, "weights"), &layer.weights)?;
+            write_npy(param_path(path, i, "bias"), &layer.bias)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gridfuncs::*;
+    use mlp::*;
+    use ndarray::stack;
+    use structopt::StructOpt;
+
+    fn freps() -> (FrepO, FrepO) {
+        let mut grid = BitGrid::default();
+        grid.set(2, 3, 5, true);
+        let frep = feature_rep(&grid);
+        grid.set(5, 1, 7, true);
+        (frep, feature_rep(&grid))
+    }
+
+    #[test]
+    fn test_forward_batch() {
+        let opt = Opt::from_iter(&["DCA", "--hidden", "8,4", "--activation", "tanh"]);
+        let mut net = MLP::new(&opt);
+        let (frep, next_frep) = freps();
+        // Give the output layer non-zero weights so that the values differ
+        net.layers[2].weights.fill(0.5);
+        let freps = stack(
+            Axis(0),
+            &[
+                frep.view().insert_axis(Axis(0)),
+                next_frep.view().insert_axis(Axis(0)),
+            ],
+        )
+        .unwrap();
+        let vals = net.forward(&freps);
+        assert_eq!(vals.shape(), &[2]);
+        assert_eq!(vals[[0]], net.forward(&frep)[[0]]);
+        assert_eq!(vals[[1]], net.forward(&next_frep)[[0]]);
+    }
+
+    #[test]
+    /// A batch of one transition should give the one-step update
+    fn test_backward_batch() {
+        let opt = Opt::from_iter(&["DCA", "--hidden", "8,4", "--alpha", "1e-4"]);
+        let mut net1 = MLP::new(&opt);
+        for layer in &mut net1.layers {
+            layer.weights.fill(0.01);
+        }
+        let mut net2 = MLP::new(&opt);
+        for (l1, l2) in net1.layers.iter().zip(net2.layers.iter_mut()) {
+            l2.weights.assign(&l1.weights);
+        }
+        let (frep, next_frep) = freps();
+        let err1 = net1.backward(&frep, 1.0, 0.5, 0.9, &next_frep);
+        let errs2 = net2.backward_batch(
+            &frep.view().insert_axis(Axis(0)),
+            &array![1.0],
+            &array![0.5],
+            &array![0.9],
+            &next_frep.view().insert_axis(Axis(0)),
+            &array![1.0],
+        );
+        assert!((err1 - errs2[[0]]).abs() < 1e-4);
+        for (l1, l2) in net1.layers.iter().zip(&net2.layers) {
+            assert!(l1.weights.all_close(&l2.weights, 1e-6));
+            assert!(l1.bias.all_close(&l2.bias, 1e-6));
+        }
+    }
+
+    #[test]
+    /// A TD update should move the state value towards the TD target
+    fn test_backward() {
+        let opt = Opt::from_iter(&["DCA", "--hidden", "16", "--alpha", "1e-5"]);
+        let mut net = MLP::new(&opt);
+        let (frep, next_frep) = freps();
+        let value = net.forward(&frep)[[0]];
+        let td_err = net.backward(&frep, 1.0, 0.0, 1.0, &next_frep);
+        assert!(td_err > 0.0);
+        assert!(net.forward(&frep)[[0]] > value);
+    }
+}
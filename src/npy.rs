@@ -0,0 +1,277 @@
+//! Reading and writing of NumPy '.npy' files, for exchanging network weights and
+//! feature representations with the Python implementation (https://github.com/tsoernes/dca).
+//! Float arrays are written as little-endian float32 and freps as uint8. Float32, float64
+//! and uint8 arrays can be read, and are always read as f32.
+use ndarray::{Array, ArrayBase, ArrayD, Data, Dimension, IxDyn};
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8] = b"\x93NUMPY";
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Array elements that can be written to a '.npy' file
+pub trait NpyElem: Copy {
+    /// The NumPy dtype of the element, e.g. '<f4'
+    const DESCR: &'static str;
+    fn write_le<W: Write>(self, writer: &mut W) -> io::Result<()>;
+}
+
+impl NpyElem for f32 {
+    const DESCR: &'static str = "<f4";
+    fn write_le<W: Write>(self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.to_le_bytes())
+    }
+}
+
+impl NpyElem for u8 {
+    const DESCR: &'static str = "|u1";
+    fn write_le<W: Write>(self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&[self])
+    }
+}
+
+/// Write an f32 array, or a u8 array such as a frep, to a '.npy' file
+/// (format version 1.0, C order)
+pub fn write_npy<P, S, D>(path: P, arr: &ArrayBase<S, D>) -> io::Result<()>
+where
+    P: AsRef<Path>,
+    S: Data,
+    S::Elem: NpyElem,
+    D: Dimension,
+{
+    let shape = match arr.shape() {
+        [n] => format!("({},)", n),
+        dims => format!(
+            "({})",
+            dims.iter()
+                .map(|d| d.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    let mut header = format!(
+        "{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}",
+        S::Elem::DESCR,
+        shape
+    );
+    // The total header length, including magic string, version and header length,
+    // must be divisible by 64 and the header must be terminated by a newline
+    let unpadded = MAGIC.len() + 2 + 2 + header.len() + 1;
+    header.extend(std::iter::repeat_n(' ', (64 - unpadded % 64) % 64));
+    header.push('\n');
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[1, 0])?;
+    writer.write_all(&(header.len() as u16).to_le_bytes())?;
+    writer.write_all(header.as_bytes())?;
+    // Iterating the array yields its elements in logical (C) order regardless of memory layout
+    for &x in arr.iter() {
+        x.write_le(&mut writer)?;
+    }
+    writer.flush()
+}
+
+/// Return the value of 'key' in the header dictionary, e.g. "'<f4'" for "'descr'"
+fn header_value<'a>(header: &'a str, key: &str) -> io::Result<&'a str> {
+    let key = format!("'{}':", key);
+    let start = header
+        .find(&key)
+        .ok_or_else(|| invalid(format!("Key {} not found in npy header", key)))?
+        + key.len();
+    let rest = header[start..].trim_start();
+    let end = if rest.starts_with('(') {
+        rest.find(')').map(|i| i + 1)
+    } else {
+        rest.find([',', '}'])
+    }
+    .ok_or_else(|| invalid(format!("Malformed npy header: {}", header)))?;
+    Ok(rest[..end].trim())
+}
+
+/// Read a '.npy' file containing a little-endian float32 or float64 array, or a uint8 array
+pub fn read_npy<P: AsRef<Path>>(path: P) -> io::Result<ArrayD<f32>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut magic = [0; 6];
+    reader.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(invalid("Not a npy file".to_string()));
+    }
+    let mut version = [0; 2];
+    reader.read_exact(&mut version)?;
+    let header_len = match version[0] {
+        1 => {
+            let mut len = [0; 2];
+            reader.read_exact(&mut len)?;
+            u16::from_le_bytes(len) as usize
+        }
+        2 | 3 => {
+            let mut len = [0; 4];
+            reader.read_exact(&mut len)?;
+            u32::from_le_bytes(len) as usize
+        }
+        v => return Err(invalid(format!("Unsupported npy version {}", v))),
+    };
+    let mut header = vec![0; header_len];
+    reader.read_exact(&mut header)?;
+    let header = String::from_utf8(header).map_err(|e| invalid(e.to_string()))?;
+
+    let descr = header_value(&header, "descr")?.trim_matches(|c| c == '\'' || c == '"');
+    let fortran_order = match header_value(&header, "fortran_order")? {
+        "True" => true,
+        "False" => false,
+        v => return Err(invalid(format!("Invalid fortran_order: {}", v))),
+    };
+    let shape = header_value(&header, "shape")?
+        .trim_matches(|c| c == '(' || c == ')')
+        .split(',')
+        .map(|d| d.trim())
+        .filter(|d| !d.is_empty())
+        .map(|d| d.parse::<usize>().map_err(|e| invalid(e.to_string())))
+        .collect::<io::Result<Vec<usize>>>()?;
+
+    let size = match descr {
+        "|u1" => 1,
+        "<f4" => 4,
+        "<f8" => 8,
+        _ => return Err(invalid(format!("Unsupported npy dtype: {}", descr))),
+    };
+    let n_elems: usize = shape.iter().product();
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+    // Checked before decoding, so that a truncated data section is an error rather than
+    // a partial element
+    if data.len() != n_elems * size {
+        return Err(invalid(format!(
+            "Expected {} bytes of data for {} elements of shape {:?}, found {}",
+            n_elems * size,
+            n_elems,
+            shape,
+            data.len()
+        )));
+    }
+    let elems: Vec<f32> = match descr {
+        "|u1" => data.iter().map(|&b| f32::from(b)).collect(),
+        "<f4" => data
+            .chunks(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect(),
+        "<f8" => data
+            .chunks(8)
+            .map(|b| {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(b);
+                f64::from_le_bytes(bytes) as f32
+            })
+            .collect(),
+        _ => unreachable!("Dtype checked above"),
+    };
+    if fortran_order {
+        // Column-major data is the row-major data of the transposed array
+        let rev_shape: Vec<usize> = shape.iter().rev().cloned().collect();
+        let arr =
+            Array::from_shape_vec(IxDyn(&rev_shape), elems).map_err(|e| invalid(e.to_string()))?;
+        let arr = arr.reversed_axes();
+        Ok(
+            Array::from_shape_vec(IxDyn(&shape), arr.iter().cloned().collect())
+                .expect("Shape matches number of elements"),
+        )
+    } else {
+        Array::from_shape_vec(IxDyn(&shape), elems).map_err(|e| invalid(e.to_string()))
+    }
+}
+
+/// Path of the file holding parameter 'name' of layer 'layer' of a network saved as 'path',
+/// e.g. 'mlp_0_bias.npy' for the path 'mlp.npy'
+pub fn param_path<L: Display>(path: &Path, layer: L, name: &str) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!("{}_{}_{}.npy", stem, layer, name))
+}
+
+/// Read a '.npy' file containing an array of the given shape
+pub fn read_npy_shaped(path: &Path, shape: &[usize]) -> io::Result<ArrayD<f32>> {
+    let arr = read_npy(path)?;
+    if arr.shape() != shape {
+        return Err(invalid(format!(
+            "Expected array of shape {:?} in {}, found {:?}",
+            shape,
+            path.display(),
+            arr.shape()
+        )));
+    }
+    Ok(arr)
+}
+
+#[cfg(test)]
+mod tests {
+    use gridfuncs::{feature_rep, BitGrid};
+    use ndarray::prelude::*;
+    use npy::*;
+    use std::env::temp_dir;
+
+    #[test]
+    fn test_roundtrip() {
+        let path = temp_dir().join("rustdca_test_roundtrip.npy");
+        let arr = Array::range(0.0, 24.0, 1.0).into_shape((2, 3, 4)).unwrap();
+        write_npy(&path, &arr).unwrap();
+        let arr2 = read_npy(&path).unwrap();
+        assert_eq!(arr2.shape(), &[2, 3, 4]);
+        assert_eq!(arr.into_dyn(), arr2);
+
+        // Views are written in logical order
+        let arr = array![[1.0, 2.0], [3.0, 4.0]];
+        write_npy(&path, &arr.t()).unwrap();
+        assert_eq!(read_npy(&path).unwrap(), arr.t().to_owned().into_dyn());
+    }
+
+    #[test]
+    /// Freps are written as uint8 arrays
+    fn test_roundtrip_frep() {
+        let path = temp_dir().join("rustdca_test_frep.npy");
+        let mut grid = BitGrid::default();
+        for &(r, c, ch) in &[(0, 0, 4), (3, 2, 10), (3, 3, 12)] {
+            grid.set(r, c, ch, true);
+        }
+        let frep = feature_rep(&grid);
+        write_npy(&path, &frep).unwrap();
+        assert_eq!(read_npy(&path).unwrap(), frep.mapv(f32::from).into_dyn());
+    }
+
+    #[test]
+    /// Case: float64 array stored in column-major (Fortran) order
+    fn test_read_f8_fortran() {
+        let path = temp_dir().join("rustdca_test_f8_fortran.npy");
+        let header = "{'descr': '<f8', 'fortran_order': True, 'shape': (2, 3), }";
+        let mut bytes = b"\x93NUMPY\x01\x00".to_vec();
+        bytes.extend(&(header.len() as u16).to_le_bytes());
+        bytes.extend(header.as_bytes());
+        // Column-major storage of [[0, 1, 2], [3, 4, 5]]
+        for x in &[0.0f64, 3.0, 1.0, 4.0, 2.0, 5.0] {
+            bytes.extend(&x.to_le_bytes());
+        }
+        ::std::fs::write(&path, bytes).unwrap();
+        let arr = read_npy(&path).unwrap();
+        assert_eq!(arr, array![[0.0, 1.0, 2.0], [3.0, 4.0, 5.0]].into_dyn());
+    }
+
+    #[test]
+    /// Case: data section shorter than the shape implies
+    fn test_read_truncated() {
+        let path = temp_dir().join("rustdca_test_truncated.npy");
+        write_npy(&path, &Array::<f32, _>::zeros((2, 3))).unwrap();
+        let mut bytes = ::std::fs::read(&path).unwrap();
+        let len = bytes.len();
+        bytes.truncate(len - 3);
+        ::std::fs::write(&path, bytes).unwrap();
+        let err = read_npy(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
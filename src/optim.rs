@@ -0,0 +1,196 @@
+use ndarray::{Array, ArrayBase, Data, Dimension, Zip};
+use std::ops::{AddAssign, MulAssign};
+use Opt;
+
+arg_enum! {
+    /// Optimizers for network parameters:
+    /// - SGD: Plain stochastic gradient descent
+    /// - Momentum: SGD with momentum ('beta1')
+    /// - RMSProp: Updates scaled by a moving average ('beta2') of squared updates
+    /// - Adam: Bias-corrected moving averages of updates ('beta1') and squared updates ('beta2')
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum OptimizerKind {
+        SGD,
+        Momentum,
+        RMSProp,
+        Adam
+    }
+}
+
+arg_enum! {
+    /// Learning rate schedules, for 't' updates:
+    /// - Constant: alpha
+    /// - Step: alpha * decay^floor(t / decay_steps)
+    /// - Exponential: alpha * decay^(t / decay_steps)
+    /// - InvTime: alpha / (1 + decay * t / decay_steps)
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum LrSchedule {
+        Constant,
+        Step,
+        Exponential,
+        InvTime
+    }
+}
+
+/// Learning rate of a network, decayed according to a schedule
+pub struct Schedule {
+    kind: LrSchedule,
+    alpha: f32,
+    decay: f32,
+    decay_steps: f32,
+    t: u64, // Number of updates thus far
+}
+
+impl Schedule {
+    pub fn new(opt: &Opt) -> Self {
+        Schedule {
+            kind: opt.lr_schedule,
+            alpha: opt.alpha,
+            decay: opt.lr_decay,
+            decay_steps: opt.lr_decay_steps as f32,
+            t: 0,
+        }
+    }
+
+    /// Learning rate for the current update; advances the schedule by one update
+    pub fn next_lr(&mut self) -> f32 {
+        let t = self.t as f32 / self.decay_steps;
+        self.t += 1;
+        match self.kind {
+            LrSchedule::Constant => self.alpha,
+            LrSchedule::Step => self.alpha * self.decay.powf(t.floor()),
+            LrSchedule::Exponential => self.alpha * self.decay.powf(t),
+            LrSchedule::InvTime => self.alpha / (1.0 + self.decay * t),
+        }
+    }
+}
+
+/// Optimizer state for a single parameter array
+pub struct Optimizer<D: Dimension> {
+    kind: OptimizerKind,
+    beta1: f32,
+    beta2: f32,
+    eps: f32,
+    moment1: Array<f32, D>, // Momentum, or moving average of updates
+    moment2: Array<f32, D>, // Moving average of squared updates
+    t: i32,                 // Number of updates, for Adam bias correction
+}
+
+impl<D: Dimension> Optimizer<D> {
+    /// Optimizer state for a parameter array of shape 'dim'
+    pub fn new(opt: &Opt, dim: D) -> Self {
+        // Moments are only allocated for the optimizers that use them
+        let empty = D::zero_index_with_ndim(dim.ndim());
+        let dim1 = match opt.optimizer {
+            OptimizerKind::Momentum | OptimizerKind::Adam => dim.clone(),
+            _ => empty.clone(),
+        };
+        let dim2 = match opt.optimizer {
+            OptimizerKind::RMSProp | OptimizerKind::Adam => dim,
+            _ => empty,
+        };
+        Optimizer {
+            kind: opt.optimizer,
+            beta1: opt.beta1,
+            beta2: opt.beta2,
+            eps: opt.optim_eps,
+            moment1: Array::zeros(dim1),
+            moment2: Array::zeros(dim2),
+            t: 0,
+        }
+    }
+
+    /// Update 'param' in the direction of 'upd' (e.g. the TD error times the
+    /// gradient of the state value) with learning rate 'lr'
+    pub fn step<S: Data<Elem = f32>>(
+        &mut self,
+        param: &mut Array<f32, D>,
+        upd: &ArrayBase<S, D>,
+        lr: f32,
+    ) {
+        let (beta1, beta2, eps) = (self.beta1, self.beta2, self.eps);
+        match self.kind {
+            OptimizerKind::SGD => param.scaled_add(lr, upd),
+            OptimizerKind::Momentum => {
+                self.moment1.mul_assign(beta1);
+                self.moment1.add_assign(upd);
+                param.scaled_add(lr, &self.moment1);
+            }
+            OptimizerKind::RMSProp => {
+                Zip::from(param)
+                    .and(upd)
+                    .and(&mut self.moment2)
+                    .apply(|p, &u, v| {
+                        *v = beta2 * *v + (1.0 - beta2) * u * u;
+                        *p += lr * u / (v.sqrt() + eps);
+                    });
+            }
+            OptimizerKind::Adam => {
+                self.t += 1;
+                let corr1 = 1.0 - beta1.powi(self.t);
+                let corr2 = 1.0 - beta2.powi(self.t);
+                Zip::from(param)
+                    .and(upd)
+                    .and(&mut self.moment1)
+                    .and(&mut self.moment2)
+                    .apply(|p, &u, m, v| {
+                        *m = beta1 * *m + (1.0 - beta1) * u;
+                        *v = beta2 * *v + (1.0 - beta2) * u * u;
+                        *p += lr * (*m / corr1) / ((*v / corr2).sqrt() + eps);
+                    });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use optim::*;
+    use structopt::StructOpt;
+
+    #[test]
+    fn test_schedules() {
+        let args = [
+            "DCA",
+            "--alpha",
+            "1",
+            "--lr_decay",
+            "0.5",
+            "--lr_decay_steps",
+            "2",
+        ];
+        let lrs = |schedule: &str| {
+            let mut args = args.to_vec();
+            args.extend(&["--lr_schedule", schedule]);
+            let mut sched = Schedule::new(&Opt::from_iter(&args));
+            (0..5).map(|_| sched.next_lr()).collect::<Vec<_>>()
+        };
+        assert_eq!(lrs("constant"), vec![1.0; 5]);
+        assert_eq!(lrs("step"), vec![1.0, 1.0, 0.5, 0.5, 0.25]);
+        let close = |lrs: Vec<f32>, expected: &[f32]| {
+            lrs.iter().zip(expected).all(|(a, b)| (a - b).abs() < 1e-6)
+        };
+        let sqrt_half = 0.5f32.sqrt();
+        assert!(close(
+            lrs("exponential"),
+            &[1.0, sqrt_half, 0.5, 0.5 * sqrt_half, 0.25]
+        ));
+        assert!(close(
+            lrs("invtime"),
+            &[1.0, 1.0 / 1.25, 1.0 / 1.5, 1.0 / 1.75, 0.5]
+        ));
+        // A decay over zero steps is rejected rather than giving NaN learning rates
+        assert!(Opt::from_iter_safe(&["DCA", "--lr_decay_steps", "0"]).is_err());
+    }
+
+    #[test]
+    /// The first Adam step moves each parameter by the learning rate
+    /// in the direction of the update, regardless of its magnitude
+    fn test_adam() {
+        let opt = Opt::from_iter(&["DCA", "--optimizer", "adam"]);
+        let mut param = array![1.0, 1.0, 1.0];
+        let mut optim = Optimizer::new(&opt, param.raw_dim());
+        optim.step(&mut param, &array![1e-3, -5.0, 0.0], 0.1);
+        assert!(param.all_close(&array![1.1, 0.9, 1.0], 1e-4));
+    }
+}
@@ -0,0 +1,256 @@
+//! Python bindings for the environment and the AA-VNet agent.
+//! Build with 'cargo build --release --features python' and import the resulting
+//! shared library as the 'rustdca' Python module. Grids and freps are exchanged as NumPy arrays
+//! of shape (ROWS, COLS, CHANNELS) (bool) and (ROWS, COLS, CHANNELS + 1) (uint8).
+use agent::{Agent, State};
+use environment::Env;
+use eventgen::Event;
+use gridfuncs::{self, BitGrid, Cell, CHANNELS, COLS, ROWS};
+use ndarray::Array;
+use numpy::{Element, PyArray1, PyArrayMethods, PyReadonlyArray3, PyUntypedArrayMethods};
+use pyo3::exceptions::{PyIOError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use std::path::Path;
+use structopt::StructOpt;
+use vnet_agent::{AAVNet, VNet};
+use Opt;
+
+/// Copy a 3D NumPy array of the given shape into an owned ndarray array
+fn from_numpy<T: Element + Copy>(
+    arr: PyReadonlyArray3<T>,
+    shape: (usize, usize, usize),
+) -> PyResult<Array<T, ::ndarray::Ix3>> {
+    if arr.shape() != [shape.0, shape.1, shape.2] {
+        return Err(PyValueError::new_err(format!(
+            "Expected array of shape {:?}, got {:?}",
+            shape,
+            arr.shape()
+        )));
+    }
+    let elems: Vec<T> = arr.as_array().iter().cloned().collect();
+    Ok(Array::from_shape_vec(shape, elems).expect("Shape checked above"))
+}
+
+/// Copy a 3D ndarray array into a new NumPy array
+fn to_numpy<'py, T: Element + Copy>(
+    py: Python<'py>,
+    arr: &Array<T, ::ndarray::Ix3>,
+) -> PyResult<Bound<'py, PyAny>> {
+    let shape = [arr.shape()[0], arr.shape()[1], arr.shape()[2]];
+    let elems: Vec<T> = arr.iter().cloned().collect();
+    Ok(PyArray1::from_vec(py, elems).reshape(shape)?.into_any())
+}
+
+fn grid_from_numpy(grid: PyReadonlyArray3<bool>) -> PyResult<BitGrid> {
+    Ok(BitGrid::from_grid(&from_numpy(
+        grid,
+        (ROWS, COLS, CHANNELS),
+    )?))
+}
+
+fn event_to_dict<'py>(py: Python<'py>, event: &Event) -> PyResult<Bound<'py, PyDict>> {
+    let dict = PyDict::new(py);
+    dict.set_item("id", event.id)?;
+    dict.set_item("time", event.time)?;
+    dict.set_item("etype", event.etype.to_string())?;
+    dict.set_item("cell", (event.cell.row, event.cell.col))?;
+    dict.set_item("ch", event.ch)?;
+    dict.set_item("to_cell", event.to_cell.as_ref().map(|c| (c.row, c.col)))?;
+    Ok(dict)
+}
+
+/// Build simulation options from keyword arguments named as the command line arguments,
+/// e.g. 'Env(call_rate=150, p_handoff=0.15, verify_grid=True)'.
+fn opt_from_kwargs(kwargs: Option<&Bound<PyDict>>) -> PyResult<Opt> {
+    let mut args = vec!["DCA".to_string()];
+    if let Some(kwargs) = kwargs {
+        for (key, val) in kwargs.iter() {
+            let key: String = key.extract()?;
+            match val.extract::<bool>() {
+                Ok(true) => args.push(format!("--{}", key)),
+                Ok(false) => {}
+                Err(_) => {
+                    args.push(format!("--{}", key));
+                    args.push(val.str()?.to_string());
+                }
+            }
+        }
+    }
+    Opt::from_iter_safe(args)
+        .and_then(|opt| opt.validate().map(|_| opt))
+        .map_err(|e| PyValueError::new_err(e.message))
+}
+
+/// A grid, its feature representation and the event to be handled
+#[pyclass(name = "State")]
+pub struct PyState {
+    state: State,
+}
+
+#[pymethods]
+impl PyState {
+    #[getter]
+    fn grid<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        to_numpy(py, &self.state.grid.to_grid())
+    }
+
+    #[getter]
+    fn frep<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        to_numpy(py, &self.state.frep)
+    }
+
+    #[getter]
+    fn event<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        event_to_dict(py, &self.state.event)
+    }
+}
+
+#[pyclass(name = "Env")]
+pub struct PyEnv {
+    env: Env,
+    event: Event,
+    dt: f64, // Time from the previous event to 'event'
+}
+
+#[pymethods]
+impl PyEnv {
+    #[new]
+    #[pyo3(signature = (**kwargs))]
+    fn new(kwargs: Option<&Bound<PyDict>>) -> PyResult<Self> {
+        let opt = opt_from_kwargs(kwargs)?;
+        let (env, event) = Env::new(&opt);
+        Ok(PyEnv {
+            env,
+            dt: event.time,
+            event,
+        })
+    }
+
+    /// The current state
+    fn state(&self) -> PyState {
+        PyState {
+            state: State {
+                grid: self.env.grid,
+                frep: self.env.frep.clone(),
+                event: self.event.clone(),
+                dt: self.dt,
+            },
+        }
+    }
+
+    /// Execute 'action' on the current event and return the reward and the next state.
+    /// Raises 'ValueError' if the action is invalid.
+    fn step(&mut self, action: Option<usize>) -> PyResult<(f32, PyState)> {
+        // An invalid action leaves the environment, and the current event, unchanged
+        let (reward, next_event) = self
+            .env
+            .step(self.event.clone(), action)
+            .map_err(|err| PyValueError::new_err(err.to_string()))?;
+        self.dt = next_event.time - self.event.time;
+        self.event = next_event;
+        Ok((reward, self.state()))
+    }
+
+    #[getter]
+    fn grid<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        to_numpy(py, &self.env.grid.to_grid())
+    }
+
+    #[getter]
+    fn event<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        event_to_dict(py, &self.event)
+    }
+}
+
+#[pyclass(name = "AAVNet")]
+pub struct PyAAVNet {
+    agent: AAVNet<VNet>,
+}
+
+#[pymethods]
+impl PyAAVNet {
+    /// Keyword arguments are named as the command line arguments,
+    /// e.g. 'AAVNet(alpha=2.52e-6, alpha_grad=5e-6)'
+    #[new]
+    #[pyo3(signature = (**kwargs))]
+    fn new(kwargs: Option<&Bound<PyDict>>) -> PyResult<Self> {
+        let opt = opt_from_kwargs(kwargs)?;
+        Ok(PyAAVNet {
+            agent: AAVNet::new(&opt),
+        })
+    }
+
+    /// Select an action (a channel, or None to block) for the state's event and return it
+    /// along with the feature representation that would result from executing it.
+    fn get_action<'py>(
+        &mut self,
+        py: Python<'py>,
+        mut state: PyRefMut<PyState>,
+    ) -> PyResult<(Option<usize>, Bound<'py, PyAny>)> {
+        let (action, next_frep) = self.agent.get_action(&mut state.state);
+        Ok((action, to_numpy(py, &next_frep)?))
+    }
+
+    fn update(
+        &mut self,
+        state: PyRef<PyState>,
+        action: Option<usize>,
+        reward: f32,
+        next_state: PyRef<PyState>,
+    ) {
+        self.agent
+            .update(&state.state, action, reward, &next_state.state)
+    }
+
+    /// Load network weights from a '.npy' file, e.g. as saved by the Python implementation
+    fn load_weights(&mut self, path: &str) -> PyResult<()> {
+        self.agent
+            .load_weights(Path::new(path))
+            .map_err(|e| PyIOError::new_err(e.to_string()))
+    }
+
+    fn save_weights(&self, path: &str) -> PyResult<()> {
+        self.agent
+            .save_weights(Path::new(path))
+            .map_err(|e| PyIOError::new_err(e.to_string()))
+    }
+}
+
+#[pyfunction(name = "feature_rep")]
+fn py_feature_rep<'py>(
+    py: Python<'py>,
+    grid: PyReadonlyArray3<bool>,
+) -> PyResult<Bound<'py, PyAny>> {
+    let grid = grid_from_numpy(grid)?;
+    to_numpy(py, &gridfuncs::feature_rep(&grid))
+}
+
+#[pyfunction(name = "get_eligible_chs")]
+fn py_get_eligible_chs(
+    grid: PyReadonlyArray3<bool>,
+    row: usize,
+    col: usize,
+) -> PyResult<Vec<usize>> {
+    if row >= ROWS || col >= COLS {
+        return Err(PyValueError::new_err(format!(
+            "Cell ({}, {}) is outside of the grid",
+            row, col
+        )));
+    }
+    let grid = grid_from_numpy(grid)?;
+    Ok(gridfuncs::get_eligible_chs(&grid, &Cell { row, col }))
+}
+
+#[pymodule]
+fn rustdca(m: &Bound<PyModule>) -> PyResult<()> {
+    m.add("ROWS", ROWS)?;
+    m.add("COLS", COLS)?;
+    m.add("CHANNELS", CHANNELS)?;
+    m.add_class::<PyState>()?;
+    m.add_class::<PyEnv>()?;
+    m.add_class::<PyAAVNet>()?;
+    m.add_function(wrap_pyfunction!(self::py_feature_rep, m)?)?;
+    m.add_function(wrap_pyfunction!(self::py_get_eligible_chs, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,100 @@
+use rand::rngs::StdRng;
+use rand::{Error, FromEntropy, RngCore, SeedableRng};
+use std::cell::RefCell;
+
+thread_local! {
+    // Seeded from the OS unless 'seed' is called on this thread
+    static RNG: RefCell<StdRng> = RefCell::new(StdRng::from_entropy());
+}
+
+/// Seed the random number generator of the current thread, which is used for
+/// event generation, exploration, replay sampling and weight initialization
+pub fn seed(seed: u64) {
+    let mut bytes = [0; 32];
+    for (i, byte) in bytes.iter_mut().take(8).enumerate() {
+        *byte = (seed >> (8 * i)) as u8;
+    }
+    RNG.with(|rng| *rng.borrow_mut() = StdRng::from_seed(bytes));
+}
+
+/// The seed of stream 'k', such as a worker thread, of a simulation seeded by 'seed'.
+/// Mixed with SplitMix64 so that the seeds of simulations with nearby seeds do not overlap.
+pub fn stream_seed(seed: u64, k: u64) -> u64 {
+    splitmix64(splitmix64(seed) ^ k)
+}
+
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// A handle to the random number generator of the current thread
+pub fn rng() -> SimRng {
+    SimRng
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SimRng;
+
+impl RngCore for SimRng {
+    fn next_u32(&mut self) -> u32 {
+        RNG.with(|rng| rng.borrow_mut().next_u32())
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        RNG.with(|rng| rng.borrow_mut().next_u64())
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        RNG.with(|rng| rng.borrow_mut().fill_bytes(dest))
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        RNG.with(|rng| rng.borrow_mut().try_fill_bytes(dest))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use environment::Env;
+    use eventgen::EType;
+    use gridfuncs::get_eligible_chs;
+    use random::*;
+    use structopt::StructOpt;
+    use Opt;
+
+    #[test]
+    /// Environments simulated from the same seed generate the same events
+    fn test_seed() {
+        let opt = Opt::from_iter(&["DCA", "-p", "0.15"]);
+        let mut runs = Vec::new();
+        for _ in 0..2 {
+            seed(7);
+            let (mut env, mut event) = Env::new(&opt);
+            let mut events = Vec::new();
+            for _ in 0..200 {
+                let action = match event.etype {
+                    EType::END => event.ch,
+                    _ => get_eligible_chs(&env.grid, &event.cell).first().cloned(),
+                };
+                event = env.step(event, action).unwrap().1;
+                events.push((event.time, event.cell.clone(), event.etype.clone()));
+            }
+            runs.push(events);
+        }
+        assert_eq!(runs[0], runs[1]);
+    }
+
+    #[test]
+    /// The stream seeds of nearby simulation seeds are distinct
+    fn test_stream_seed() {
+        let mut seeds: Vec<u64> = (0..8)
+            .flat_map(|seed| (0..8).map(move |k| stream_seed(seed, k)))
+            .collect();
+        seeds.sort();
+        seeds.dedup();
+        assert_eq!(seeds.len(), 64);
+    }
+}
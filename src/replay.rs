@@ -0,0 +1,293 @@
+use agent::State;
+use gridfuncs::{FrepO, FrepsO, CHANNELS, COLS, ROWS};
+use ndarray::{Array, Array1, Axis};
+use rand::Rng;
+use random::rng;
+use Opt;
+
+arg_enum! {
+    /// Sampling of mini-batches from the experience replay buffer:
+    /// - Uniform: Uniformly at random
+    /// - Prioritized: Proportional to the absolute TD error of the last update on the
+    ///   transition, raised to the power 'priority_exp' (Schaul et al. 2016).
+    ///   New transitions have the highest priority seen so far. Updates are weighted by
+    ///   importance sampling, with exponent 'priority_is_exp'.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum ReplayKind {
+        Uniform,
+        Prioritized
+    }
+}
+
+/// Added to the absolute TD error so that no transition has zero probability of being sampled
+const PRIORITY_EPS: f32 = 1e-3;
+
+pub struct Transition {
+    pub frep: FrepO,
+    pub reward: f32,
+    pub dt: f64, // Duration of the transition, in minutes
+    pub next_frep: FrepO,
+}
+
+impl Transition {
+    pub fn new(state: &State, reward: f32, next_state: &State) -> Self {
+        Transition {
+            frep: state.frep.clone(),
+            reward,
+            dt: next_state.dt,
+            next_frep: next_state.frep.clone(),
+        }
+    }
+}
+
+/// A batch of transitions, with freps of shape (B, ROWS, COLS, CHANNELS + 1)
+pub struct Batch {
+    pub freps: FrepsO,
+    pub rewards: Array1<f32>,
+    pub dts: Array1<f64>,
+    pub next_freps: FrepsO,
+    pub weights: Array1<f32>, // Importance-sampling weight of the update on each transition
+}
+
+impl Batch {
+    /// A batch of transitions, each weighted equally
+    pub fn new<'a, I: IntoIterator<Item = &'a Transition>>(transitions: I) -> Self {
+        let transitions: Vec<&Transition> = transitions.into_iter().collect();
+        let n = transitions.len();
+        let mut batch = Batch {
+            freps: Array::zeros((n, ROWS, COLS, CHANNELS + 1)),
+            rewards: Array::zeros(n),
+            dts: Array::zeros(n),
+            next_freps: Array::zeros((n, ROWS, COLS, CHANNELS + 1)),
+            weights: Array::ones(n),
+        };
+        for (i, t) in transitions.iter().enumerate() {
+            batch.freps.subview_mut(Axis(0), i).assign(&t.frep);
+            batch.rewards[[i]] = t.reward;
+            batch.dts[[i]] = t.dt;
+            batch
+                .next_freps
+                .subview_mut(Axis(0), i)
+                .assign(&t.next_frep);
+        }
+        batch
+    }
+}
+
+/// A binary tree where each node holds the sum of the priorities of the leaves below it,
+/// for sampling and updating priorities in logarithmic time
+struct SumTree {
+    n_leaves: usize, // A power of two, no less than the capacity
+    nodes: Vec<f32>, // The root at index 1, with the children of node 'i' at '2i' and '2i + 1'
+}
+
+impl SumTree {
+    fn new(capacity: usize) -> Self {
+        let n_leaves = capacity.next_power_of_two();
+        SumTree {
+            n_leaves,
+            nodes: vec![0.0; 2 * n_leaves],
+        }
+    }
+
+    fn total(&self) -> f32 {
+        self.nodes[1]
+    }
+
+    fn get(&self, idx: usize) -> f32 {
+        self.nodes[self.n_leaves + idx]
+    }
+
+    fn set(&mut self, idx: usize, priority: f32) {
+        let mut i = self.n_leaves + idx;
+        self.nodes[i] = priority;
+        // Recompute rather than adjust the sums so that rounding errors do not accumulate
+        while i > 1 {
+            i /= 2;
+            self.nodes[i] = self.nodes[2 * i] + self.nodes[2 * i + 1];
+        }
+    }
+
+    /// The index of the leaf where the cumulative sum of priorities exceeds 'u'
+    fn find(&self, mut u: f32) -> usize {
+        let mut i = 1;
+        while i < self.n_leaves {
+            let left = self.nodes[2 * i];
+            // Rounding errors may leave 'u' slightly above the sum of priorities,
+            // in which case the last leaf with a non-zero priority is found
+            if u < left || self.nodes[2 * i + 1] == 0.0 {
+                i *= 2;
+            } else {
+                u -= left;
+                i = 2 * i + 1;
+            }
+        }
+        i - self.n_leaves
+    }
+}
+
+/// A fixed capacity buffer of transitions, replacing the oldest transition once full
+pub struct ReplayBuffer {
+    kind: ReplayKind,
+    capacity: usize,
+    batch_size: usize,
+    priority_exp: f32,
+    priority_is_exp: f32,
+    transitions: Vec<Transition>,
+    priorities: SumTree, // Sampling priority of each transition; unused for uniform sampling
+    max_priority: f32,
+    next: usize, // Index of the transition to replace once full
+}
+
+impl ReplayBuffer {
+    pub fn new(opt: &Opt, kind: ReplayKind) -> Self {
+        ReplayBuffer {
+            kind,
+            capacity: opt.replay_size,
+            batch_size: opt.batch_size,
+            priority_exp: opt.priority_exp,
+            priority_is_exp: opt.priority_is_exp,
+            transitions: Vec::with_capacity(opt.replay_size),
+            priorities: SumTree::new(opt.replay_size),
+            max_priority: 1.0,
+            next: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.transitions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transitions.is_empty()
+    }
+
+    pub fn push(&mut self, transition: Transition) {
+        if self.transitions.len() < self.capacity {
+            self.transitions.push(transition);
+        } else {
+            self.transitions[self.next] = transition;
+        }
+        self.priorities.set(self.next, self.max_priority);
+        self.next = (self.next + 1) % self.capacity;
+    }
+
+    pub fn get(&self, idx: usize) -> &Transition {
+        &self.transitions[idx]
+    }
+
+    /// The batch of transitions at 'idxs'. With prioritized sampling, the update on each
+    /// transition is weighted by '(N * P(i))^-priority_is_exp' to correct for the bias
+    /// of sampling with probability 'P(i)', relative to the largest weight in the batch.
+    pub fn batch(&self, idxs: &[usize]) -> Batch {
+        let mut batch = Batch::new(idxs.iter().map(|&idx| &self.transitions[idx]));
+        if self.kind == ReplayKind::Prioritized {
+            let n = self.transitions.len() as f32;
+            let total = self.priorities.total();
+            for (w, &idx) in batch.weights.iter_mut().zip(idxs) {
+                *w = (n * self.priorities.get(idx) / total).powf(-self.priority_is_exp);
+            }
+            let max_w = batch.weights.fold(0.0, |max: f32, &w| max.max(w));
+            batch.weights /= max_w;
+        }
+        batch
+    }
+
+    /// Return the indecies of a mini-batch of transitions, sampled with replacement
+    pub fn sample(&self) -> Vec<usize> {
+        let mut rng = rng();
+        let n = self.transitions.len();
+        match self.kind {
+            ReplayKind::Uniform => (0..self.batch_size).map(|_| rng.gen_range(0, n)).collect(),
+            ReplayKind::Prioritized => {
+                let total = self.priorities.total();
+                (0..self.batch_size)
+                    .map(|_| self.priorities.find(rng.gen::<f32>() * total))
+                    .collect()
+            }
+        }
+    }
+
+    /// Update the priority of a transition given the TD error of an update on it
+    pub fn update_priority(&mut self, idx: usize, td_err: f32) {
+        if self.kind == ReplayKind::Prioritized {
+            let priority = (td_err.abs() + PRIORITY_EPS).powf(self.priority_exp);
+            self.priorities.set(idx, priority);
+            self.max_priority = self.max_priority.max(priority);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gridfuncs::*;
+    use replay::*;
+    use structopt::StructOpt;
+
+    fn transition(reward: f32) -> Transition {
+        let frep = feature_rep(&BitGrid::default());
+        Transition {
+            frep: frep.clone(),
+            reward,
+            dt: 0.1,
+            next_frep: frep,
+        }
+    }
+
+    #[test]
+    fn test_replay() {
+        let opt = Opt::from_iter(&[
+            "DCA",
+            "--replay_size",
+            "3",
+            "--batch_size",
+            "50",
+            "--priority_exp",
+            "1",
+        ]);
+        let mut replay = ReplayBuffer::new(&opt, ReplayKind::Prioritized);
+        for i in 0..4 {
+            replay.push(transition(i as f32));
+        }
+        // The oldest transition has been replaced
+        assert_eq!(replay.len(), 3);
+        assert_eq!(replay.get(0).reward, 3.0);
+        // Transitions with small TD errors are rarely sampled
+        replay.update_priority(0, 0.0);
+        replay.update_priority(1, 0.0);
+        replay.update_priority(2, 1e3);
+        let idxs = replay.sample();
+        assert_eq!(idxs.len(), 50);
+        assert!(idxs.iter().filter(|&&i| i == 2).count() > 45);
+    }
+
+    #[test]
+    /// Transitions are found by the cumulative sum of priorities, and the updates on
+    /// transitions that are sampled more often are weighted less
+    fn test_prioritized_weights() {
+        let opt = Opt::from_iter(&[
+            "DCA",
+            "--replay_size",
+            "5",
+            "--priority_exp",
+            "1",
+            "--priority_is_exp",
+            "1",
+        ]);
+        let mut replay = ReplayBuffer::new(&opt, ReplayKind::Prioritized);
+        for i in 0..5 {
+            replay.push(transition(i as f32));
+        }
+        for (idx, &priority) in [1.0, 3.0, 1.0, 0.5, 0.5].iter().enumerate() {
+            replay.priorities.set(idx, priority);
+        }
+        let total = replay.priorities.total();
+        assert_eq!(replay.priorities.find(0.5), 0);
+        assert_eq!(replay.priorities.find(1.5), 1);
+        assert_eq!(replay.priorities.find(4.5), 2);
+        assert_eq!(replay.priorities.find(5.2), 3);
+        assert_eq!(replay.priorities.find(total * 1.01), 4);
+        let batch = replay.batch(&[0, 1, 2]);
+        assert!(batch.weights.all_close(&array![1.0, 1.0 / 3.0, 1.0], 1e-4));
+    }
+}
@@ -0,0 +1,100 @@
+use agent::Action;
+use eventgen::EType;
+use gridfuncs::{n_used, BitGrid, COLS, ROWS};
+use Opt;
+
+arg_enum! {
+    /// Reward functions, given the grid after an event has been handled:
+    /// - ChannelsInUse: The number of channels in use
+    /// - BlockPenalty: -1 if a call was blocked, 0 otherwise
+    /// - HoffDropPenalty: -1 if a new call was blocked and -'hoff_weight' if a hand-off was dropped
+    /// - Fairness: The number of channels in use, less 'fairness_weight' times the sum over cells
+    ///   of the absolute deviation of the number of channels in use in the cell from the mean
+    /// - TimeIntegrated: The number of channels in use, integrated over the time until the next event
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum RewardKind {
+        ChannelsInUse,
+        BlockPenalty,
+        HoffDropPenalty,
+        Fairness,
+        TimeIntegrated
+    }
+}
+
+pub struct RewardFn {
+    kind: RewardKind,
+    hoff_weight: f32,
+    fairness_weight: f32,
+}
+
+impl RewardFn {
+    pub fn new(opt: &Opt) -> Self {
+        let kind = match opt.reward {
+            Some(kind) => kind,
+            None if opt.continuous_time => RewardKind::TimeIntegrated,
+            None => RewardKind::ChannelsInUse,
+        };
+        RewardFn {
+            kind,
+            hoff_weight: opt.hoff_weight,
+            fairness_weight: opt.fairness_weight,
+        }
+    }
+
+    /// Reward for the action taken on an event of type 'etype', given the resulting 'grid'
+    /// and the time 'dt' until the next event
+    pub fn reward(&self, grid: &BitGrid, etype: &EType, action: Action, dt: f64) -> f32 {
+        let blocked = action.is_none();
+        match self.kind {
+            RewardKind::ChannelsInUse => n_used(grid) as f32,
+            RewardKind::BlockPenalty => -(blocked as usize as f32),
+            RewardKind::HoffDropPenalty => match *etype {
+                EType::NEW if blocked => -1.0,
+                EType::HOFF if blocked => -self.hoff_weight,
+                _ => 0.0,
+            },
+            RewardKind::Fairness => {
+                let cell_used: Vec<f32> = iproduct!(0..ROWS, 0..COLS)
+                    .map(|(r, c)| grid.chs(r, c).count_ones() as f32)
+                    .collect();
+                let mean = cell_used.iter().sum::<f32>() / cell_used.len() as f32;
+                let deviation = cell_used.iter().fold(0.0, |sum, &n| sum + (n - mean).abs());
+                n_used(grid) as f32 - self.fairness_weight * deviation
+            }
+            RewardKind::TimeIntegrated => n_used(grid) as f32 * dt as f32,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gridfuncs::*;
+    use reward::*;
+    use structopt::StructOpt;
+
+    #[test]
+    fn test_rewards() {
+        let reward_fn = |args: &[&str]| {
+            let mut argv = vec!["DCA"];
+            argv.extend(args);
+            RewardFn::new(&Opt::from_iter(&argv))
+        };
+        let mut grid = BitGrid::default();
+        grid.set(0, 0, 1, true);
+        grid.set(0, 0, 2, true);
+        let rfn = reward_fn(&[]);
+        assert_eq!(rfn.reward(&grid, &EType::NEW, None, 0.5), 2.0);
+        let rfn = reward_fn(&["--continuous_time"]);
+        assert_eq!(rfn.reward(&grid, &EType::NEW, None, 0.5), 1.0);
+        let rfn = reward_fn(&["--reward", "blockpenalty"]);
+        assert_eq!(rfn.reward(&grid, &EType::HOFF, None, 0.5), -1.0);
+        assert_eq!(rfn.reward(&grid, &EType::NEW, Some(3), 0.5), 0.0);
+        let rfn = reward_fn(&["--reward", "hoffdroppenalty", "--hoff_weight", "4"]);
+        assert_eq!(rfn.reward(&grid, &EType::HOFF, None, 0.5), -4.0);
+        assert_eq!(rfn.reward(&grid, &EType::NEW, None, 0.5), -1.0);
+        // All channels in use are in one cell: deviations of 2 - 2/49 and 48 * 2/49
+        let rfn = reward_fn(&["--reward", "fairness", "--fairness_weight", "0.5"]);
+        let reward = rfn.reward(&grid, &EType::END, Some(1), 0.5);
+        assert!((reward - (2.0 - 0.5 * 4.0 * 48.0 / 49.0)).abs() < 1e-5);
+    }
+}
@@ -1,4 +1,5 @@
 use chrono::Local;
+use error::EnvError;
 
 #[derive(Default)]
 pub struct Stats {
@@ -21,6 +22,10 @@ pub struct Stats {
     n_rejected_new: i32,
     // Number of rejected hand-offs
     n_rejected_hoff: i32,
+    // Number of ongoing calls moved to another channel, on end events or by repacking
+    n_reassigned: i32,
+    // Number of illegal actions that were substituted by another action
+    n_illegal_actions: i32,
 
     // Block prob during each log iter period
     block_probs: Vec<f64>,
@@ -64,6 +69,22 @@ impl Stats {
         self.n_ended += 1;
     }
 
+    pub fn event_reassign(&mut self) {
+        self.n_reassigned += 1;
+    }
+
+    pub fn n_reassigned(&self) -> i32 {
+        self.n_reassigned
+    }
+
+    pub fn event_illegal_action(&mut self) {
+        self.n_illegal_actions += 1;
+    }
+
+    pub fn n_illegal_actions(&self) -> i32 {
+        self.n_illegal_actions
+    }
+
     fn cums(&mut self) -> (f64, f64, f64) {
         let cum_block_prob_new = self.n_rejected_new as f64 / (self.n_arrivals_new as f64 + 1.0);
         let cum_block_prob_hoff = self.n_rejected_hoff as f64 / (self.n_arrivals_hoff as f64 + 1.0);
@@ -89,16 +110,11 @@ impl Stats {
         self.i = i;
     }
 
+    /// Print the statistics of the simulation, then check that the number of calls in
+    /// progress agrees with the number of accepted calls that have not ended.
     /// t: Simulation time
     /// n_in_progress: Number of calls currently in progress at simulation end
-    pub fn report_end(&mut self, t: f64, n_in_progress: usize) {
-        // Count how many calls _should_ currently be in progress, based on the number
-        // of reported incoming and terminated calls
-        let delta = self.n_arrivals_new + self.n_arrivals_hoff
-            - self.n_rejected_new
-            - self.n_rejected_hoff
-            - self.n_ended;
-        assert_eq!(delta, n_in_progress as i32);
+    pub fn report_end(&mut self, t: f64, n_in_progress: usize) -> Result<(), EnvError> {
         let dt = (Local::now().timestamp() - self.start_time) as f64;
         let m = dt / 60.0;
         let h = dt - m * 60.0;
@@ -120,7 +136,9 @@ impl Stats {
              n_ended: {},
              n_curr_rejected_new: {},
              n_rejected_new: {},
-             n_rejected_hoff: {}",
+             n_rejected_hoff: {},
+             n_reassigned: {},
+             n_illegal_actions: {}",
             self.n_curr_arrivals_new,
             self.n_arrivals_new,
             self.n_accepted_new,
@@ -128,9 +146,17 @@ impl Stats {
             self.n_ended,
             self.n_curr_rejected_new,
             self.n_rejected_new,
-            self.n_rejected_hoff
+            self.n_rejected_hoff,
+            self.n_reassigned,
+            self.n_illegal_actions
         );
 
+        if self.n_reassigned > 0 {
+            println!("Channel reassignments: {}", self.n_reassigned);
+        }
+        if self.n_illegal_actions > 0 {
+            println!("Illegal actions: {}", self.n_illegal_actions);
+        }
         let (cum_block_prob_new, cum_block_prob_hoff, cum_block_prob_tot) = self.cums();
         println!(
             "Blocking probability: {:.4} for new calls",
@@ -142,5 +168,18 @@ impl Stats {
                 cum_block_prob_hoff, cum_block_prob_tot
             );
         }
+        // Count how many calls _should_ currently be in progress, based on the number
+        // of reported incoming and terminated calls
+        let delta = self.n_arrivals_new + self.n_arrivals_hoff
+            - self.n_rejected_new
+            - self.n_rejected_hoff
+            - self.n_ended;
+        if delta != n_in_progress as i32 {
+            return Err(EnvError::CallCountMismatch {
+                expected: delta,
+                found: n_in_progress,
+            });
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,310 @@
+use agent::*;
+use eventgen::EType;
+use exploration::Exploration;
+use gridfuncs::{get_eligible_chs, incremental_freps, FrepO, CHANNELS, COLS};
+use ndarray::{Array, Array1, Array2, Axis};
+use npy::{read_npy, write_npy};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::path::Path;
+use Opt;
+
+/// Number of columns of a saved q-table: the state key followed by the q-value of each channel
+const TABLE_COLS: usize = 4 + CHANNELS;
+
+/// Compressed state for table lookup
+#[derive(Hash, Eq, PartialEq, Ord, PartialOrd, Clone, Copy, Debug)]
+struct TabState {
+    cell: usize,    // Flat index of the focal cell
+    end: bool,      // Whether the event is an end event; these have different actions
+    n_elig: usize,  // Number of eligible channels at the focal cell
+    n_inuse: usize, // Number of channels in use at the focal cell
+}
+
+impl TabState {
+    fn new(state: &State) -> Self {
+        let cell = &state.event.cell;
+        TabState {
+            cell: cell.row * COLS + cell.col,
+            end: state.event.etype == EType::END,
+            n_elig: get_eligible_chs(&state.grid, cell).len(),
+            n_inuse: state.grid.chs(cell.row, cell.col).count_ones() as usize,
+        }
+    }
+}
+
+/// A table-lookup agent with a q-value for each channel in each compressed state
+/// (focal cell, number of eligible channels, number of channels in use at the focal cell),
+/// as in the early DCA-RL work of Nie & Haykin (1999).
+/// Q-values are updated with Q-learning or SARSA ('--agent'), under the discounted
+/// return criterion if a discount factor is given ('--gamma') and the average reward
+/// criterion otherwise.
+pub struct Tabular {
+    sarsa: bool,
+    alpha: f32,
+    alpha_avg: f32,
+    criterion: Criterion,
+    avg_reward: f32,
+    exploration: Exploration,
+    qval_init: f32, // Initial q-value of each channel in a state not visited before
+    qvals: HashMap<TabState, Array1<f32>>,
+    // SARSA: the state, action, reward less average reward, and discount of the last
+    // transition, which is updated once the action in the next state has been selected.
+    // With parallel environments there is one for each environment, in the order they
+    // are updated on; 'None' if the last action was a block.
+    pending: VecDeque<Option<(TabState, usize, f32, f32)>>,
+}
+
+impl Tabular {
+    fn qvals(&mut self, tstate: TabState) -> &mut Array1<f32> {
+        let init = self.qval_init;
+        self.qvals
+            .entry(tstate)
+            .or_insert_with(|| Array::from_elem(CHANNELS, init))
+    }
+
+    /// Update the q-value of 'ch' in 'tstate' given the reward, less the average reward,
+    /// and the discounted value of the next state
+    fn update_qval(&mut self, tstate: TabState, ch: usize, reward: f32, next_val: f32) {
+        let alpha = self.alpha;
+        let qvals = self.qvals(tstate);
+        let td_err = reward + next_val - qvals[[ch]];
+        qvals[[ch]] += alpha * td_err;
+        if self.criterion.is_avg_reward() {
+            self.avg_reward += self.alpha_avg * td_err;
+        }
+    }
+}
+
+impl Agent for Tabular {
+    fn new(opt: &Opt) -> Self {
+        Tabular {
+            sarsa: opt.agent == AgentKind::SARSA,
+            alpha: opt.alpha_table,
+            alpha_avg: opt.alpha_avg,
+            criterion: Criterion::new(opt),
+            avg_reward: opt.optimistic_init.unwrap_or(0.0),
+            exploration: Exploration::new(opt),
+            qval_init: opt.optimistic_init.unwrap_or(0.0),
+            qvals: HashMap::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn get_action(&mut self, state: &mut State) -> (Action, FrepO) {
+        let chs = available_chs(state);
+        let tstate = TabState::new(state);
+        let action = if chs.is_empty() {
+            assert_ne!(
+                state.event.etype,
+                EType::END,
+                "No channels in use on end event!"
+            );
+            None
+        } else {
+            let qvals = self.qvals(tstate).select(Axis(0), &chs);
+            Some(chs[self.exploration.select(&qvals)])
+        };
+        if let Some(Some((p_tstate, p_ch, p_reward, p_discount))) = self.pending.pop_front() {
+            // A state without actions has no value
+            let next_val = action.map_or(0.0, |ch| self.qvals(tstate)[[ch]]);
+            self.update_qval(p_tstate, p_ch, p_reward, p_discount * next_val);
+        }
+        match action {
+            Some(ch) => {
+                let frep = incremental_freps(
+                    &state.grid,
+                    &state.frep,
+                    &state.event.cell,
+                    &state.event.etype,
+                    &[ch],
+                )
+                .slice_move(s![0, .., .., ..]);
+                (action, frep)
+            }
+            None => (None, state.frep.clone()),
+        }
+    }
+
+    fn update(&mut self, state: &State, action: Action, reward: f32, next_state: &State) {
+        let ch = match action {
+            Some(ch) => ch,
+            None => {
+                if self.sarsa {
+                    self.pending.push_back(None);
+                }
+                return;
+            }
+        };
+        let tstate = TabState::new(state);
+        let reward = reward - self.criterion.avg_reward(self.avg_reward, next_state.dt);
+        let discount = self.criterion.discount(next_state.dt);
+        if self.sarsa {
+            self.pending.push_back(Some((tstate, ch, reward, discount)));
+        } else {
+            let next_chs = available_chs(next_state);
+            let next_val = if next_chs.is_empty() {
+                0.0
+            } else {
+                let next_qvals = self.qvals(TabState::new(next_state));
+                next_chs
+                    .iter()
+                    .fold(f32::MIN, |max, &ch| max.max(next_qvals[[ch]]))
+            };
+            self.update_qval(tstate, ch, reward, discount * next_val);
+        }
+    }
+
+    /// Load a q-table as saved by 'save_weights'
+    fn load_weights(&mut self, path: &Path) -> io::Result<()> {
+        let table = read_npy(path)?;
+        if table.ndim() != 2 || table.shape()[1] != TABLE_COLS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Expected q-table with {} columns in {}, found array of shape {:?}",
+                    TABLE_COLS,
+                    path.display(),
+                    table.shape()
+                ),
+            ));
+        }
+        self.qvals = table
+            .outer_iter()
+            .map(|row| {
+                let tstate = TabState {
+                    cell: row[[0]] as usize,
+                    end: row[[1]] != 0.0,
+                    n_elig: row[[2]] as usize,
+                    n_inuse: row[[3]] as usize,
+                };
+                (tstate, row.slice(s![4..]).to_owned())
+            })
+            .collect();
+        Ok(())
+    }
+
+    /// Save the q-table to a '.npy' file as a 2D array with a row for each visited state,
+    /// consisting of the state (cell, end, n_elig, n_inuse) followed by the q-value of each channel
+    fn save_weights(&self, path: &Path) -> io::Result<()> {
+        let mut tstates: Vec<&TabState> = self.qvals.keys().collect();
+        tstates.sort();
+        let mut table = Array2::zeros((tstates.len(), TABLE_COLS));
+        for (mut row, tstate) in table.outer_iter_mut().zip(tstates) {
+            row[[0]] = tstate.cell as f32;
+            row[[1]] = tstate.end as usize as f32;
+            row[[2]] = tstate.n_elig as f32;
+            row[[3]] = tstate.n_inuse as f32;
+            row.slice_mut(s![4..]).assign(&self.qvals[tstate]);
+        }
+        write_npy(path, &table)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use eventgen::Event;
+    use gridfuncs::*;
+    use std::env::temp_dir;
+    use structopt::StructOpt;
+    use tabular::*;
+
+    fn state(grid: BitGrid, etype: EType) -> State {
+        State {
+            frep: feature_rep(&grid),
+            grid,
+            dt: 0.1,
+            event: Event {
+                id: 0,
+                time: 0.0,
+                etype,
+                cell: Cell { row: 2, col: 3 },
+                ch: None,
+                to_cell: None,
+            },
+        }
+    }
+
+    /// The state after assigning 'ch' in the focal cell, with an arrival in the same cell
+    fn assigned(state_: &State, ch: usize) -> State {
+        let mut grid = state_.grid;
+        grid.set(state_.event.cell.row, state_.event.cell.col, ch, true);
+        state(grid, EType::NEW)
+    }
+
+    #[test]
+    fn test_qlearning() {
+        let opt = Opt::from_iter(&[
+            "DCA",
+            "--agent",
+            "qlearning",
+            "--alpha_table",
+            "0.5",
+            "--gamma",
+            "0.5",
+        ]);
+        let mut agent = Tabular::new(&opt);
+        let mut state = state(BitGrid::default(), EType::NEW);
+        let (action, frep) = agent.get_action(&mut state);
+        let ch = action.unwrap();
+        let mut next_state = assigned(&state, ch);
+        assert_eq!(frep, next_state.frep);
+        // Actions in the next state are valued at zero
+        agent.update(&state, action, 10.0, &next_state);
+        let tstate = TabState::new(&state);
+        assert_eq!(agent.qvals(tstate)[[ch]], 5.0);
+        next_state.event.etype = EType::END;
+        agent.qvals(TabState::new(&next_state))[[ch]] = 2.0;
+        agent.update(&state, action, 10.0, &next_state);
+        assert_eq!(agent.qvals(tstate)[[ch]], 5.0 + 0.5 * (10.0 + 1.0 - 5.0));
+
+        let path = temp_dir().join("rustdca_test_qtable.npy");
+        agent.save_weights(&path).unwrap();
+        let mut agent2 = Tabular::new(&opt);
+        agent2.load_weights(&path).unwrap();
+        assert_eq!(agent.qvals, agent2.qvals);
+    }
+
+    #[test]
+    /// Channels in states not visited before have the optimistic initial q-value,
+    /// also for the discounted return criterion
+    fn test_optimistic_init() {
+        let opt = Opt::from_iter(&["DCA", "--optimistic_init", "50", "--gamma", "0.9"]);
+        let mut agent = Tabular::new(&opt);
+        let state = state(BitGrid::default(), EType::NEW);
+        assert_eq!(
+            agent.qvals(TabState::new(&state)),
+            &Array::from_elem(CHANNELS, 50.0)
+        );
+    }
+
+    #[test]
+    /// The average reward per minute is scaled by the time between events
+    fn test_continuous_time() {
+        let opt = Opt::from_iter(&["DCA", "--continuous_time", "--alpha_table", "1"]);
+        let mut agent = Tabular::new(&opt);
+        agent.avg_reward = 100.0;
+        let mut state = state(BitGrid::default(), EType::NEW);
+        let (action, _) = agent.get_action(&mut state);
+        let mut next_state = assigned(&state, action.unwrap());
+        next_state.dt = 0.25;
+        agent.update(&state, action, 30.0, &next_state);
+        let qval = agent.qvals(TabState::new(&state))[[action.unwrap()]];
+        assert_eq!(qval, 30.0 - 100.0 * 0.25);
+    }
+
+    #[test]
+    /// SARSA updates are made once the next action is known
+    fn test_sarsa() {
+        let opt = Opt::from_iter(&["DCA", "--agent", "sarsa", "--alpha_table", "1"]);
+        let mut agent = Tabular::new(&opt);
+        let mut state = state(BitGrid::default(), EType::NEW);
+        let (action, _) = agent.get_action(&mut state);
+        let mut next_state = assigned(&state, action.unwrap());
+        agent.update(&state, action, 10.0, &next_state);
+        let tstate = TabState::new(&state);
+        assert_eq!(agent.qvals(tstate)[[action.unwrap()]], 0.0);
+        agent.get_action(&mut next_state);
+        assert_eq!(agent.qvals(tstate)[[action.unwrap()]], 10.0);
+    }
+}
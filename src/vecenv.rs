@@ -0,0 +1,313 @@
+use agent::{Action, Agent, State};
+use ctrlc::set_handler;
+use environment::Env;
+use error::EnvError;
+use eventgen::Event;
+use gridfuncs::{feature_rep, n_used, BitGrid, FrepO};
+use random;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use Opt;
+
+enum Request {
+    Step(Event, Action),
+    // Report the blocking probability, then acknowledge on the given channel
+    ReportLogIter(i32, Sender<()>),
+}
+
+// The reward, the next event, the grid and its frep after a step, whether the step
+// included a repacking move, and the action executed in place of an illegal action, if any
+type Reply = (f32, Event, BitGrid, FrepO, bool, Option<Action>);
+
+/// Step 'env' and collect the reply
+fn step_env(env: &mut Env, event: Event, action: Action) -> Result<Reply, EnvError> {
+    env.step(event, action).map(|(reward, next_event)| {
+        (
+            reward,
+            next_event,
+            env.grid,
+            env.frep.clone(),
+            env.repacked.is_some(),
+            env.substituted,
+        )
+    })
+}
+
+/// An environment that is stepped on its own thread
+struct Worker {
+    requests: Sender<Request>,
+    replies: Receiver<Result<Reply, EnvError>>,
+    handle: JoinHandle<Env>,
+}
+
+impl Worker {
+    /// Step 'env' on a new thread, with its random number generator seeded by 'seed' if given
+    fn new(mut env: Env, seed: Option<u64>) -> Self {
+        let (requests, req_rx) = channel();
+        let (reply_tx, replies) = channel();
+        let handle = thread::spawn(move || {
+            if let Some(seed) = seed {
+                random::seed(seed);
+            }
+            // Runs until the request channel is closed, then hands back the environment
+            for request in req_rx {
+                match request {
+                    Request::Step(event, action) => {
+                        let reply = step_env(&mut env, event, action);
+                        reply_tx.send(reply).expect("VecEnv dropped");
+                    }
+                    Request::ReportLogIter(i, ack) => {
+                        env.stats.report_log_iter(i);
+                        ack.send(()).expect("VecEnv dropped");
+                    }
+                }
+            }
+            env
+        });
+        Worker {
+            requests,
+            replies,
+            handle,
+        }
+    }
+
+    fn request(&self, request: Request) {
+        self.requests
+            .send(request)
+            .expect("Environment thread panicked");
+    }
+
+    fn reply(&self) -> Result<Reply, EnvError> {
+        self.replies.recv().expect("Environment thread panicked")
+    }
+}
+
+enum Envs {
+    Lockstep(Vec<Env>),
+    Threaded(Vec<Worker>),
+}
+
+/// A number of independent environments that are stepped together, either in lockstep
+/// on the calling thread or on a thread each
+pub struct VecEnv {
+    envs: Envs,
+}
+
+impl VecEnv {
+    /// Initialize 'opt.n_envs' environments and return the first event of each
+    pub fn new(opt: &Opt) -> (VecEnv, Vec<Event>) {
+        let (envs, events): (Vec<Env>, Vec<Event>) = (0..opt.n_envs).map(|_| Env::new(opt)).unzip();
+        let envs = if opt.threaded {
+            // Each thread has its own random number generator, seeded differently
+            // from that of the calling thread and those of the other threads
+            let seeds = (1..).map(|k| opt.seed.map(|seed| random::stream_seed(seed, k)));
+            Envs::Threaded(
+                envs.into_iter()
+                    .zip(seeds)
+                    .map(|(env, seed)| Worker::new(env, seed))
+                    .collect(),
+            )
+        } else {
+            Envs::Lockstep(envs)
+        };
+        (VecEnv { envs }, events)
+    }
+
+    /// Execute an action on the event of each environment. Return the reward, the next event,
+    /// the grid and its frep after the step, whether it included a repacking move and the
+    /// substituted action, if any, for each environment. All environments are stepped even if one of them fails,
+    /// in which case the first error is returned.
+    pub fn step(&mut self, events: Vec<Event>, actions: &[Action]) -> Result<Vec<Reply>, EnvError> {
+        let replies: Vec<Result<Reply, EnvError>> = match self.envs {
+            Envs::Lockstep(ref mut envs) => izip!(envs, events, actions)
+                .map(|(env, event, &action)| step_env(env, event, action))
+                .collect(),
+            Envs::Threaded(ref workers) => {
+                for (worker, event, &action) in izip!(workers, events, actions) {
+                    worker.request(Request::Step(event, action));
+                }
+                workers.iter().map(|worker| worker.reply()).collect()
+            }
+        };
+        replies.into_iter().collect()
+    }
+
+    /// Report the blocking probability of each environment
+    pub fn report_log_iter(&mut self, i: i32) {
+        match self.envs {
+            Envs::Lockstep(ref mut envs) => {
+                for (k, env) in envs.iter_mut().enumerate() {
+                    print!("Env {}: ", k);
+                    env.stats.report_log_iter(i);
+                }
+            }
+            Envs::Threaded(ref workers) => {
+                // One at a time, so that the reports are not interleaved
+                for (k, worker) in workers.iter().enumerate() {
+                    print!("Env {}: ", k);
+                    let (ack, done) = channel();
+                    worker.request(Request::ReportLogIter(i, ack));
+                    done.recv().expect("Environment thread panicked");
+                }
+            }
+        }
+    }
+
+    /// Stop any environment threads and return the environments
+    pub fn into_envs(self) -> Vec<Env> {
+        match self.envs {
+            Envs::Lockstep(envs) => envs,
+            Envs::Threaded(workers) => workers
+                .into_iter()
+                .map(|worker| {
+                    drop(worker.requests);
+                    worker.handle.join().expect("Environment thread panicked")
+                })
+                .collect(),
+        }
+    }
+}
+
+/// The state of an environment at its first event, with an empty grid
+fn initial_state(event: Event) -> State {
+    let grid = BitGrid::default();
+    State {
+        frep: feature_rep(&grid),
+        grid,
+        dt: event.time,
+        event,
+    }
+}
+
+/// Simulate 'opt.n_envs' environments with a shared agent, which selects actions for
+/// and is updated on the states of all environments at once. Each environment is
+/// simulated for 'opt.n_events' events.
+pub fn simulate_vec<A: Agent>(opt: &Opt) {
+    // Create a CTRL-C key handler
+    let running = Arc::new(AtomicBool::new(true));
+    let r = running.clone();
+    set_handler(move || {
+        r.store(false, Ordering::SeqCst);
+    })
+    .expect("Error setting Ctrl-C handler");
+
+    if let Some(seed) = opt.seed {
+        random::seed(seed);
+    }
+    let (mut venv, events) = VecEnv::new(opt);
+    let mut agent: A = A::new(opt);
+    if let Some(ref path) = opt.load_weights {
+        agent
+            .load_weights(Path::new(path))
+            .expect("Failed to load weights");
+    }
+    let mut states: Vec<State> = events.into_iter().map(initial_state).collect();
+    let (mut actions, mut next_freps): (Vec<Action>, Vec<FrepO>) =
+        agent.get_actions(&mut states).into_iter().unzip();
+    for i in 0..opt.n_events {
+        if !running.load(Ordering::SeqCst) {
+            println!("Premature exit");
+            break;
+        }
+        let events = states.iter().map(|state| state.event.clone()).collect();
+        let results = match venv.step(events, &actions) {
+            Ok(results) => results,
+            Err(err) => {
+                println!("\nSimulation stopped: {}", err);
+                break;
+            }
+        };
+        // Neither a repacking move by the environment nor a substituted action is part
+        // of the agent's afterstate
+        let frep_mismatch =
+            |(&(_, _, _, ref frep, repacked, substituted), next_frep): (&Reply, &FrepO)| {
+                !repacked && substituted.is_none() && next_frep != frep
+            };
+        if opt.verify_frep && results.iter().zip(&next_freps).any(frep_mismatch) {
+            println!("\nSimulation stopped: {}", EnvError::FrepMismatch);
+            break;
+        }
+        let executed: Vec<Action> = results
+            .iter()
+            .zip(&actions)
+            .map(|(reply, &action)| reply.5.unwrap_or(action))
+            .collect();
+        let mut rewards = Vec::with_capacity(states.len());
+        let mut next_states: Vec<State> = izip!(results, &states)
+            .map(|((reward, next_event, grid, frep, _, _), state)| {
+                rewards.push(reward);
+                State {
+                    grid,
+                    frep,
+                    dt: next_event.time - state.event.time,
+                    event: next_event,
+                }
+            })
+            .collect();
+        agent.update_batch(&states, &executed, &rewards, &next_states);
+        let (a, f) = agent.get_actions(&mut next_states).into_iter().unzip();
+        actions = a;
+        next_freps = f;
+        states = next_states;
+
+        if i > 0 && i % opt.log_iter == 0 {
+            venv.report_log_iter(i);
+        }
+    }
+    for (k, (mut env, state)) in venv.into_envs().into_iter().zip(&states).enumerate() {
+        print!("\nEnv {}:", k);
+        if let Err(err) = env.stats.report_end(state.event.time, n_used(&env.grid)) {
+            println!("\n{}", err);
+        }
+    }
+    if let Some(ref path) = opt.save_weights {
+        agent
+            .save_weights(Path::new(path))
+            .expect("Failed to save weights");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use structopt::StructOpt;
+    use vecenv::*;
+    use vnet_agent::{AAVNet, VNet};
+
+    #[test]
+    /// The afterstate frep of the action selected for each environment in a batch
+    /// is the frep of the grid of that environment after the step
+    fn test_vecenv() {
+        let opt = Opt::from_iter(&["DCA", "--n_envs", "3", "--threaded"]);
+        let (mut venv, events) = VecEnv::new(&opt);
+        let mut agent = AAVNet::<VNet>::new(&opt);
+        let mut states: Vec<State> = events.into_iter().map(initial_state).collect();
+        for _ in 0..300 {
+            let (actions, freps): (Vec<Action>, Vec<FrepO>) =
+                agent.get_actions(&mut states).into_iter().unzip();
+            let events = states.iter().map(|state| state.event.clone()).collect();
+            let results = venv.step(events, &actions).unwrap();
+            assert_eq!(results.len(), 3);
+            let next_states: Vec<State> = izip!(results, freps, &states)
+                .map(
+                    |((reward, next_event, grid, frep, _, substituted), next_frep, state)| {
+                        assert!(substituted.is_none());
+                        assert_eq!(next_frep, frep);
+                        assert_eq!(frep, feature_rep(&grid));
+                        assert_eq!(reward, n_used(&grid) as f32);
+                        State {
+                            grid,
+                            frep,
+                            dt: next_event.time - state.event.time,
+                            event: next_event,
+                        }
+                    },
+                )
+                .collect();
+            states = next_states;
+        }
+        assert_eq!(venv.into_envs().len(), 3);
+    }
+}
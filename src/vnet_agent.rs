@@ -1,55 +1,242 @@
 use agent::*;
 use eventgen::EType;
+use exploration::Exploration;
 use gridfuncs::{
-    afterstates, argpmax1, get_eligible_chs, get_inuse_chs, incremental_freps, Frep, FrepO, FrepsO,
-    CHANNELS, COLS, ROWS,
+    apply_diff, apply_diffs, frep_diffs, get_eligible_chs, incremental_freps, Frep, FrepDiff,
+    FrepO, CHANNELS, COLS, ROWS,
 };
-use ndarray::Data;
-use ndarray::{Array, Array1, Array2, ArrayBase, ArrayView2, Axis, Dimension};
-use std::f32::MIN as fmin;
-use std::ops::AddAssign;
-use std::ops::SubAssign;
+use ndarray::{stack, Array, Array1, Array2, ArrayBase, Axis, Dimension};
+use ndarray::{Data, IntoDimension, Ix2, Ix4};
+use npy::{param_path, read_npy, write_npy};
+use optim::{Optimizer, Schedule};
+use replay::{Batch, ReplayBuffer, Transition};
+use std::io;
+use std::ops::MulAssign;
+use std::path::{Path, PathBuf};
+use Opt;
 
-const WDIM: usize = ROWS * COLS * (CHANNELS + 1);
+/// Number of elements in a flattened frep
+pub const WDIM: usize = ROWS * COLS * (CHANNELS + 1);
+
+/// Network input: freps converted to floating point and reshaped to 'shape'
+pub fn net_input<S, D, E>(freps: &ArrayBase<S, D>, shape: E) -> Array<f32, E::Dim>
+where
+    S: Data<Elem = u8>,
+    D: Dimension,
+    E: IntoDimension,
+{
+    freps
+        .mapv(f32::from)
+        .into_shape(shape)
+        .expect("Freps reshape fail")
+}
 
 pub trait Net {
-    fn forward<S: Data<Elem = f32>, D: Dimension>(
+    fn new(opt: &Opt) -> Self;
+
+    fn forward<S: Data<Elem = u8>, D: Dimension>(&mut self, freps: &ArrayBase<S, D>)
+        -> Array1<f32>;
+
+    /// State values of the freps that result from applying each of 'diffs' to 'frep'
+    fn forward_diffs<S: Data<Elem = u8>>(
         &mut self,
-        freps: &ArrayBase<S, D>,
-    ) -> Array1<f32>;
+        frep: &Frep<S>,
+        diffs: &[FrepDiff],
+    ) -> Array1<f32> {
+        self.forward(&apply_diffs(frep, diffs))
+    }
 
-    fn backward<S: Data<Elem = f32>>(
+    /// Update the network on the transition from 'frep' to 'next_frep' and return the TD error,
+    /// 'reward - avg_reward + discount * V(next_frep) - V(frep)'. The discount is 1 for the
+    /// average reward criterion and the average reward is 0 for the discounted return criterion.
+    fn backward<S: Data<Elem = u8>>(
         &mut self,
         frep: &Frep<S>,
         reward: f32,
         avg_reward: f32,
+        discount: f32,
         next_frep: &Frep<S>,
     ) -> f32;
+
+    /// Update the network on a batch of transitions, given freps and next freps of shape
+    /// (B, ROWS, COLS, CHANNELS + 1), with the mean of the updates for each transition
+    /// scaled by its weight in 'weights'.
+    /// Neither eligibility traces nor emphasis (for emphatic TD) are used.
+    /// Return the TD error of each transition.
+    fn backward_batch<S: Data<Elem = u8>>(
+        &mut self,
+        freps: &ArrayBase<S, Ix4>,
+        rewards: &Array1<f32>,
+        avg_rewards: &Array1<f32>,
+        discounts: &Array1<f32>,
+        next_freps: &ArrayBase<S, Ix4>,
+        weights: &Array1<f32>,
+    ) -> Array1<f32>;
+
+    /// Load the network parameters from '.npy' file(s)
+    fn load(&mut self, path: &Path) -> io::Result<()>;
+
+    /// Save the network parameters to '.npy' file(s)
+    fn save(&self, path: &Path) -> io::Result<()>;
+}
+
+arg_enum! {
+    /// State value networks:
+    /// - VNet: Linear network
+    /// - MLP: Multi-layer perceptron
+    /// - HexConv: Convolutional network over the hexagonal grid
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum NetKind {
+        VNet,
+        MLP,
+        HexConv
+    }
+}
+
+arg_enum! {
+    /// Update rules for the weights of a linear value network, all in the average reward setting:
+    /// - TD0: Semi-gradient TD(lambda)
+    /// - GTD2: GTD2 (Sutton et al. 2009), without eligibility traces
+    /// - TDC: TDC(lambda) (Sutton et al. 2009; Maei 2011)
+    /// - TDCVariant: The TDC variant of the original AA-VNet
+    /// - ETD: Emphatic TD(lambda, beta) (Hallak et al. 2016), with a decaying follow-on trace
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum UpdateRule {
+        TD0,
+        GTD2,
+        TDC,
+        TDCVariant,
+        ETD
+    }
 }
 
 pub struct VNet {
-    alpha: f32,
+    schedule: Schedule, // Learning rate for the network weights
+    optimizer: Optimizer<Ix2>,
     alpha_grad: f32,
+    lambda: Option<f32>, // Trace decay; one-step updates if not given
+    update_rule: UpdateRule,
+    followon_decay: f32,
+    followon: f32,          // 'F_t': follow-on trace for emphatic TD
     grad_corr: Array2<f32>, // 'w_t': gradient correction weight
     weights: Array2<f32>,   // 'theta_t': neural network weights
+    traces: Array2<f32>,    // 'e_t': eligibility traces
+    with_traces: bool,      // Load and save 'grad_corr' and 'traces' along with the weights
 }
 
 impl VNet {
-    fn new(alpha: f32, alpha_grad: f32) -> Self {
-        VNet {
-            alpha,
-            alpha_grad,
-            grad_corr: Array::zeros((WDIM, 1)),
-            weights: Array::zeros((WDIM, 1)),
+    /// Load network weights and, optionally, gradient correction weights and eligibility
+    /// traces from '.npy' files. The arrays may be of any shape with
+    /// 'ROWS * COLS * (CHANNELS + 1)' elements in C order, such as the
+    /// (ROWS, COLS, CHANNELS + 1) shape of a frep.
+    pub fn load_npy(
+        &mut self,
+        weights: &Path,
+        grad_corr: Option<&Path>,
+        traces: Option<&Path>,
+    ) -> io::Result<()> {
+        self.weights = read_wdim(weights)?;
+        if let Some(grad_corr) = grad_corr {
+            self.grad_corr = read_wdim(grad_corr)?;
+        }
+        if let Some(traces) = traces {
+            self.traces = read_wdim(traces)?;
+        }
+        Ok(())
+    }
+
+    /// Save network weights and, optionally, gradient correction weights and eligibility
+    /// traces to '.npy' files as (WDIM, 1) arrays.
+    pub fn save_npy(
+        &self,
+        weights: &Path,
+        grad_corr: Option<&Path>,
+        traces: Option<&Path>,
+    ) -> io::Result<()> {
+        write_npy(weights, &self.weights)?;
+        if let Some(grad_corr) = grad_corr {
+            write_npy(grad_corr, &self.grad_corr)?;
+        }
+        if let Some(traces) = traces {
+            write_npy(traces, &self.traces)?;
+        }
+        Ok(())
+    }
+
+    /// Paths of the gradient correction weights and eligibility traces of a network
+    /// saved as 'path', if they are loaded and saved along with the weights
+    fn aux_paths(&self, path: &Path) -> (Option<PathBuf>, Option<PathBuf>) {
+        if self.with_traces {
+            (
+                Some(param_path(path, 0, "grad_corr")),
+                Some(param_path(path, 0, "traces")),
+            )
+        } else {
+            (None, None)
         }
     }
 }
 
+/// TD errors of a batch of transitions
+pub fn batch_td_errs(
+    values: &Array1<f32>,
+    next_values: &Array1<f32>,
+    rewards: &Array1<f32>,
+    avg_rewards: &Array1<f32>,
+    discounts: &Array1<f32>,
+) -> Array1<f32> {
+    Array::from_vec(
+        izip!(values, next_values, rewards, avg_rewards, discounts)
+            .map(|(v, next_v, r, avg_r, d)| r - avg_r + d * next_v - v)
+            .collect(),
+    )
+}
+
+fn read_wdim(path: &Path) -> io::Result<Array2<f32>> {
+    let arr = read_npy(path)?;
+    if arr.len() != WDIM {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Expected {} weights in {}, found array of shape {:?}",
+                WDIM,
+                path.display(),
+                arr.shape()
+            ),
+        ));
+    }
+    Ok(arr.into_shape((WDIM, 1)).expect("Weights reshape"))
+}
+
 impl Net for VNet {
+    fn new(opt: &Opt) -> Self {
+        let mut weights = Array::zeros((WDIM, 1));
+        if let Some(init) = opt.optimistic_init {
+            // Lacking a bias, value the empty grid, where every channel is eligible
+            // in every cell, at 'init' through the eligible channel count features
+            weights
+                .slice_mut(s![CHANNELS..;CHANNELS + 1, ..])
+                .fill(init / (ROWS * COLS * CHANNELS) as f32);
+        }
+        VNet {
+            schedule: Schedule::new(opt),
+            optimizer: Optimizer::new(opt, Ix2(WDIM, 1)),
+            alpha_grad: opt.alpha_grad,
+            lambda: opt.lambda,
+            update_rule: opt.update_rule,
+            followon_decay: opt.followon_decay,
+            followon: 0.0,
+            grad_corr: Array::zeros((WDIM, 1)),
+            weights,
+            traces: Array::zeros((WDIM, 1)),
+            with_traces: opt.with_traces,
+        }
+    }
+
     /// Forward pass. Calculate the state value of one (3D array) or multiple (4D) freps.
     fn forward<S, D>(&mut self, freps: &ArrayBase<S, D>) -> Array1<f32>
     where
-        S: Data<Elem = f32>,
+        S: Data<Elem = u8>,
         D: Dimension,
     {
         let n_freps = if freps.ndim() == 3 {
@@ -59,143 +246,293 @@ impl Net for VNet {
         };
         // A linear neural network is a simple inner vector product (dot product)
         // between the input and the network weights
-        freps
-            .view()
-            .into_shape((n_freps, WDIM))
-            .expect("Freps flatten fail")
+        net_input(freps, (n_freps, WDIM))
             .dot(&self.weights)
             .into_shape(n_freps)
             .expect("State val flatten fail")
     }
 
+    /// The value of a linear network changes by the weighted sum of the feature differences
+    fn forward_diffs<S: Data<Elem = u8>>(
+        &mut self,
+        frep: &Frep<S>,
+        diffs: &[FrepDiff],
+    ) -> Array1<f32> {
+        let value = self.forward(frep)[[0]];
+        diffs
+            .iter()
+            .map(|diff| {
+                diff.iter().fold(value, |value, &(r, c, f, d)| {
+                    value + f32::from(d) * self.weights[[(r * COLS + c) * (CHANNELS + 1) + f, 0]]
+                })
+            })
+            .collect()
+    }
+
     /// Backward pass.
-    fn backward<S: Data<Elem = f32>>(
+    fn backward<S: Data<Elem = u8>>(
         &mut self,
         frep: &Frep<S>,
         reward: f32,
         avg_reward: f32,
+        discount: f32,
         next_frep: &Frep<S>,
     ) -> f32 {
-        let value = self.forward(frep);
-        assert_eq!(value.shape(), &[1]);
-        let value = value[[0]];
-        let next_value = self.forward(next_frep)[[0]];
-        let td_err = reward - avg_reward + next_value - value;
-        let inp_rv: ArrayView2<f32> = frep.view().into_shape((1, WDIM)).expect("Frep reshape3");
-        let inp_cv = inp_rv.t();
-        let next_inp_cv: ArrayView2<f32> = next_frep
-            .view()
-            .into_shape((WDIM, 1))
-            .expect("Frep reshape4");
-        let dot = inp_rv.dot(&self.grad_corr);
-        assert_eq!(dot.shape(), &[1, 1]);
-        let dot = dot[[0, 0]];
-        let c = -2.0 * self.alpha;
-        let grads: Array2<f32> =
-            (c * td_err) * inp_cv.to_owned() + c * avg_reward - (c * dot) * next_inp_cv.to_owned();
-        assert_eq!(grads.shape(), self.weights.shape());
-        self.weights.sub_assign(&grads);
-        let upd = (self.alpha_grad * (td_err - dot)) * inp_cv.to_owned();
-        assert_eq!(upd.shape(), self.grad_corr.shape());
-        self.grad_corr.add_assign(&upd);
+        let inp_cv = net_input(frep, (WDIM, 1));
+        let next_inp_cv = net_input(next_frep, (WDIM, 1));
+        let value = inp_cv.t().dot(&self.weights)[[0, 0]];
+        let next_value = next_inp_cv.t().dot(&self.weights)[[0, 0]];
+        let td_err = reward - avg_reward + discount * next_value - value;
+        let lambda = self.lambda.unwrap_or(0.0);
+        // Features of the current state, weighted by their emphasis for emphatic TD,
+        // are added to the decaying traces. With lambda = 0 the traces equal
+        // the (emphasized) features of the current state.
+        let emphasis = match self.update_rule {
+            UpdateRule::ETD => {
+                self.followon = self.followon_decay * self.followon + 1.0;
+                lambda + (1.0 - lambda) * self.followon
+            }
+            _ => 1.0,
+        };
+        self.traces.mul_assign(discount * lambda);
+        self.traces.scaled_add(emphasis, &inp_cv);
+        let dot = inp_cv.t().dot(&self.grad_corr)[[0, 0]];
+        let trace_dot = self.traces.t().dot(&self.grad_corr)[[0, 0]];
+        // The weight update, before scaling by the learning rate
+        let mut upd: Array2<f32> = match self.update_rule {
+            UpdateRule::TD0 | UpdateRule::ETD => td_err * &self.traces,
+            UpdateRule::GTD2 => dot * &inp_cv,
+            UpdateRule::TDC => td_err * &self.traces,
+            UpdateRule::TDCVariant => 2.0 * (td_err * &self.traces + avg_reward),
+        };
+        let next_coef = match self.update_rule {
+            UpdateRule::TD0 | UpdateRule::ETD => None,
+            UpdateRule::GTD2 => Some(-dot),
+            UpdateRule::TDC => Some(-(1.0 - lambda) * trace_dot),
+            UpdateRule::TDCVariant => Some(-2.0 * (1.0 - lambda) * trace_dot),
+        };
+        if let Some(coef) = next_coef {
+            upd.scaled_add(discount * coef, &next_inp_cv);
+        }
+        let lr = self.schedule.next_lr();
+        self.optimizer.step(&mut self.weights, &upd, lr);
+        match self.update_rule {
+            UpdateRule::GTD2 | UpdateRule::TDC | UpdateRule::TDCVariant => {
+                self.grad_corr
+                    .scaled_add(self.alpha_grad * td_err, &self.traces);
+                self.grad_corr.scaled_add(-self.alpha_grad * dot, &inp_cv);
+            }
+            UpdateRule::TD0 | UpdateRule::ETD => {}
+        }
         td_err
     }
+
+    fn backward_batch<S: Data<Elem = u8>>(
+        &mut self,
+        freps: &ArrayBase<S, Ix4>,
+        rewards: &Array1<f32>,
+        avg_rewards: &Array1<f32>,
+        discounts: &Array1<f32>,
+        next_freps: &ArrayBase<S, Ix4>,
+        weights: &Array1<f32>,
+    ) -> Array1<f32> {
+        let n = freps.len_of(Axis(0));
+        let inp = net_input(freps, (n, WDIM));
+        let next_inp = net_input(next_freps, (n, WDIM));
+        let values = inp
+            .dot(&self.weights)
+            .into_shape(n)
+            .expect("Values flatten fail");
+        let next_values = next_inp
+            .dot(&self.weights)
+            .into_shape(n)
+            .expect("Values flatten fail");
+        let td_errs = batch_td_errs(&values, &next_values, rewards, avg_rewards, discounts);
+        let dots = inp
+            .dot(&self.grad_corr)
+            .into_shape(n)
+            .expect("Dots flatten fail");
+        let (w_td_errs, w_dots) = (&td_errs * weights, &dots * weights);
+        // Sum over the batch of the features of each (next) state weighted by 'coefs'
+        let weighted_sum = |x: &Array2<f32>, coefs: &Array1<f32>| -> Array2<f32> {
+            x.t()
+                .dot(&coefs.view().into_shape((n, 1)).expect("Coefs reshape"))
+        };
+        // The weight update, as for a one-step update where the traces equal the features
+        let mut upd: Array2<f32> = match self.update_rule {
+            UpdateRule::TD0 | UpdateRule::ETD | UpdateRule::TDC => weighted_sum(&inp, &w_td_errs),
+            UpdateRule::GTD2 => weighted_sum(&inp, &w_dots),
+            UpdateRule::TDCVariant => {
+                2.0 * (weighted_sum(&inp, &w_td_errs) + (avg_rewards * weights).scalar_sum())
+            }
+        };
+        let next_coef = match self.update_rule {
+            UpdateRule::TD0 | UpdateRule::ETD => None,
+            UpdateRule::GTD2 | UpdateRule::TDC => Some(-1.0),
+            UpdateRule::TDCVariant => Some(-2.0),
+        };
+        if let Some(coef) = next_coef {
+            upd.scaled_add(coef, &weighted_sum(&next_inp, &(discounts * &w_dots)));
+        }
+        upd /= n as f32;
+        let lr = self.schedule.next_lr();
+        self.optimizer.step(&mut self.weights, &upd, lr);
+        match self.update_rule {
+            UpdateRule::GTD2 | UpdateRule::TDC | UpdateRule::TDCVariant => {
+                self.grad_corr.scaled_add(
+                    self.alpha_grad / n as f32,
+                    &weighted_sum(&inp, &(&w_td_errs - &w_dots)),
+                );
+            }
+            UpdateRule::TD0 | UpdateRule::ETD => {}
+        }
+        td_errs
+    }
+
+    fn load(&mut self, path: &Path) -> io::Result<()> {
+        let (grad_corr, traces) = self.aux_paths(path);
+        self.load_npy(path, grad_corr.as_deref(), traces.as_deref())
+    }
+
+    fn save(&self, path: &Path) -> io::Result<()> {
+        let (grad_corr, traces) = self.aux_paths(path);
+        self.save_npy(path, grad_corr.as_deref(), traces.as_deref())
+    }
 }
 
 pub struct AAVNet<N: Net> {
+    criterion: Criterion,
     alpha_avg: f32,
     net: N,
+    exploration: Exploration,
+    replay: Option<ReplayBuffer>,
     avg_reward: f32,
 }
 
 impl<N: Net> AAVNet<N> {
-    /// Return the state value of each possible afterstate, along with the corresponding
-    /// feature representations.
+    /// Return the state value of each possible afterstate, along with the differences
+    /// between the feature representation of the state and those of the afterstates.
     /// Performs hand-off look-ahead (HLA) for hand-off departures.
-    fn get_qvals(&mut self, state: &mut State, chs: &Vec<usize>) -> (Array1<f32>, FrepsO) {
-        match state.event.to_cell {
+    fn get_qvals(&mut self, state: &State, chs: &[usize]) -> (Array1<f32>, Vec<FrepDiff>) {
+        let diffs = frep_diffs(&state.grid, &state.event.cell, &state.event.etype, chs);
+        let qvals = match state.event.to_cell {
             Some(ref to_cell) => {
                 // HLA. This event is is known to be a hand-off departure and the next
-                // event is known to be a hand-off arrival.
-                let mut end_astates =
-                    afterstates(&state.grid, &state.event.cell, &state.event.etype, chs);
-                let freps = incremental_freps(
-                    &mut state.grid,
-                    &state.frep,
-                    &state.event.cell,
-                    &state.event.etype,
-                    chs,
-                );
-                let mut n_tot = 0;
-                let ha_chs: Vec<Vec<usize>> = end_astates
-                    .outer_iter()
-                    .map(|end_astate| {
-                        let echs = get_eligible_chs(&end_astate, &to_cell);
-                        n_tot += echs.len();
-                        echs
+                // event is known to be a hand-off arrival. The afterstates of the hand-off
+                // arrival on each eligible channel, found by freeing the departing channel
+                // in a single scratch grid, are evaluated as the differences from the
+                // departure afterstate appended to those of the departure.
+                let (r, c) = (state.event.cell.row, state.event.cell.col);
+                let mut end_astate = state.grid;
+                let ha_diffs: Vec<Vec<FrepDiff>> = chs
+                    .iter()
+                    .zip(&diffs)
+                    .map(|(&ch, diff)| {
+                        end_astate.set(r, c, ch, false);
+                        let echs = get_eligible_chs(&end_astate, to_cell);
+                        let ha_diffs = frep_diffs(&end_astate, to_cell, &EType::HOFF, &echs)
+                            .into_iter()
+                            .map(|ha_diff| diff.iter().chain(&ha_diff).cloned().collect())
+                            .collect();
+                        end_astate.set(r, c, ch, true);
+                        ha_diffs
                     })
                     .collect();
-                debug_assert_eq!(chs.len(), ha_chs.len());
-                debug_assert_eq!(ha_chs.len(), end_astates.len_of(Axis(0)));
-                debug_assert_eq!(end_astates.len_of(Axis(0)), freps.len_of(Axis(0)));
-                // Shold assert lengths here
-                let qvals = if n_tot > 0 {
-                    let mut qvals = Array1::zeros(chs.len());
-                    for (i, mut end_astate, frep, iha_chs) in izip!(
-                        0..,
-                        end_astates.outer_iter_mut(),
-                        freps.outer_iter(),
-                        ha_chs.into_iter()
-                    ) {
-                        if !iha_chs.is_empty() {
-                            let ha_freps = incremental_freps(
-                                &mut end_astate,
-                                &frep,
-                                &to_cell,
-                                &EType::HOFF,
-                                &iha_chs,
-                            );
-                            let hla_qvals = self.net.forward(&ha_freps);
-                            qvals[[i]] = hla_qvals
-                                .fold(fmin, |max, &elem| if elem > max { elem } else { max });
-                        }
-                    }
-                    qvals
+                if ha_diffs.iter().all(Vec::is_empty) {
+                    self.net.forward_diffs(&state.frep, &diffs)
                 } else {
-                    self.net.forward(&freps)
-                };
-                (qvals, freps)
+                    let qvals = ha_diffs
+                        .iter()
+                        .map(|ha_diffs| match ha_diffs.len() {
+                            0 => 0.0,
+                            _ => self
+                                .net
+                                .forward_diffs(&state.frep, ha_diffs)
+                                .fold(f32::MIN, |max, &elem| max.max(elem)),
+                        })
+                        .collect();
+                    Array::from_vec(qvals)
+                }
             }
-            _ => {
-                let freps = incremental_freps(
-                    &mut state.grid,
-                    &state.frep,
-                    &state.event.cell,
-                    &state.event.etype,
-                    chs,
-                );
-                (self.net.forward(&freps), freps)
+            None => self.net.forward_diffs(&state.frep, &diffs),
+        };
+        (qvals, diffs)
+    }
+
+    /// Select one of 'chs', which is not empty, given the values of its afterstates and
+    /// return the feature representation of the afterstate
+    fn select_action(&mut self, state: &State, chs: &[usize]) -> (Action, FrepO) {
+        let (qvals, diffs) = self.get_qvals(state, chs);
+        let idx = self.exploration.select(&qvals);
+        debug!("qvals: {:?}, idx: {:?}, ch: {}", qvals, idx, chs[idx]);
+        let mut frep = state.frep.clone();
+        apply_diff(&mut frep, &diffs[idx]);
+        (Some(chs[idx]), frep)
+    }
+
+    /// Update the network on a batch of transitions and return the TD error of each
+    fn backward_batch(&mut self, batch: &Batch) -> Array1<f32> {
+        let (criterion, avg_reward) = (self.criterion, self.avg_reward);
+        self.net.backward_batch(
+            &batch.freps,
+            &batch.rewards,
+            &batch.dts.mapv(|dt| criterion.avg_reward(avg_reward, dt)),
+            &batch.dts.mapv(|dt| criterion.discount(dt)),
+            &batch.next_freps,
+            &batch.weights,
+        )
+    }
+
+    /// Add transitions to the replay buffer and update the network on a sampled
+    /// mini-batch. Return the mean TD error of the mini-batch.
+    fn replay_update(&mut self, transitions: Vec<Transition>) -> f32 {
+        let (idxs, batch) = {
+            let replay = self.replay.as_mut().expect("No replay buffer");
+            for transition in transitions {
+                replay.push(transition);
             }
+            let idxs = replay.sample();
+            let batch = replay.batch(&idxs);
+            (idxs, batch)
+        };
+        let errs = self.backward_batch(&batch);
+        let replay = self.replay.as_mut().expect("No replay buffer");
+        for (&idx, &err) in idxs.iter().zip(&errs) {
+            replay.update_priority(idx, err);
+        }
+        errs.scalar_sum() / errs.len() as f32
+    }
+
+    /// Update the average reward estimate given the TD error of an update,
+    /// or the mean TD error of a batch update
+    fn update_avg_reward(&mut self, err: f32) {
+        assert!(
+            !err.is_nan(),
+            "NaN loss on backprop. Current avg. reward: {}",
+            self.avg_reward
+        );
+        if self.criterion.is_avg_reward() {
+            self.avg_reward += self.alpha_avg * err;
         }
     }
 }
 
-impl Agent for AAVNet<VNet> {
-    fn new(alpha: f32, alpha_avg: f32, alpha_grad: f32) -> AAVNet<VNet> {
+impl<N: Net> Agent for AAVNet<N> {
+    fn new(opt: &Opt) -> AAVNet<N> {
         AAVNet {
-            alpha_avg,
-            net: VNet::new(alpha, alpha_grad),
-            avg_reward: 0.0,
+            criterion: Criterion::new(opt),
+            alpha_avg: opt.alpha_avg,
+            net: N::new(opt),
+            exploration: Exploration::new(opt),
+            replay: opt.replay.map(|kind| ReplayBuffer::new(opt, kind)),
+            avg_reward: opt.optimistic_init.unwrap_or(0.0),
         }
     }
 
     /// Select an action and return the Frep
     /// which would result from executing that action.
     fn get_action(&mut self, state: &mut State) -> (Action, FrepO) {
-        let chs = match state.event.etype {
-            EType::END => get_inuse_chs(&state.grid, &state.event.cell),
-            _ => get_eligible_chs(&state.grid, &state.event.cell),
-        };
+        let chs = available_chs(state);
         debug!("Available actions for {:?}: {:?}", state.event, chs);
         if chs.is_empty() {
             assert_ne!(
@@ -205,27 +542,289 @@ impl Agent for AAVNet<VNet> {
             );
             return (None, state.frep.clone());
         }
-        let (qvals, freps) = self.get_qvals(state, &chs);
-        // Strictly greedy action selection; no exploration is performed.
-        let (idx, _qval) = argpmax1(&qvals).unwrap();
-        debug!("qvals: {:?}, idx: {:?}, ch: {}", qvals, idx, chs[idx]);
-        (Some(chs[idx]), freps.slice_move(s![idx, .., .., ..]))
+        self.select_action(state, &chs)
     }
 
-    fn update(&mut self, state: &State, _action: Action, reward: i32, next_state: &State) {
+    fn update(&mut self, state: &State, _action: Action, reward: f32, next_state: &State) {
         // Knowing the action is not relevant for updating state value nets when
         // both the state and next state are given.
-        let err = self.net.backward(
-            &state.frep,
-            reward as f32,
-            self.avg_reward,
-            &next_state.frep,
-        );
-        assert!(
-            !err.is_nan(),
-            "NaN loss on backprop. Current avg. reward: {}",
-            self.avg_reward
-        );
-        self.avg_reward += self.alpha_avg * err;
+        let err = if self.replay.is_some() {
+            self.replay_update(vec![Transition::new(state, reward, next_state)])
+        } else {
+            let (criterion, avg_reward) = (self.criterion, self.avg_reward);
+            self.net.backward(
+                &state.frep,
+                reward,
+                criterion.avg_reward(avg_reward, next_state.dt),
+                criterion.discount(next_state.dt),
+                &next_state.frep,
+            )
+        };
+        self.update_avg_reward(err);
+    }
+
+    /// Select an action for each state. The afterstates of all states that do not
+    /// require hand-off look-ahead are evaluated with a single forward pass.
+    fn get_actions(&mut self, states: &mut [State]) -> Vec<(Action, FrepO)> {
+        let chss: Vec<Vec<usize>> = states.iter().map(available_chs).collect();
+        let mut batched_freps = Vec::new();
+        for (state, chs) in states.iter_mut().zip(&chss) {
+            if !chs.is_empty() && state.event.to_cell.is_none() {
+                batched_freps.push(incremental_freps(
+                    &state.grid,
+                    &state.frep,
+                    &state.event.cell,
+                    &state.event.etype,
+                    chs,
+                ));
+            }
+        }
+        let batched_qvals = if batched_freps.is_empty() {
+            Array1::zeros(0)
+        } else {
+            let views: Vec<_> = batched_freps.iter().map(|freps| freps.view()).collect();
+            self.net
+                .forward(&stack(Axis(0), &views).expect("Frep shape mismatch"))
+        };
+        let mut batched_freps = batched_freps.into_iter();
+        let mut offset = 0;
+        let mut actions = Vec::with_capacity(states.len());
+        for (state, chs) in states.iter_mut().zip(chss) {
+            if chs.is_empty() {
+                assert_ne!(
+                    state.event.etype,
+                    EType::END,
+                    "No channels in use on end event!"
+                );
+                actions.push((None, state.frep.clone()));
+                continue;
+            }
+            if state.event.to_cell.is_some() {
+                actions.push(self.select_action(state, &chs));
+                continue;
+            }
+            let qvals = batched_qvals
+                .slice(s![offset..offset + chs.len()])
+                .to_owned();
+            offset += chs.len();
+            let freps = batched_freps.next().unwrap();
+            let idx = self.exploration.select(&qvals);
+            actions.push((Some(chs[idx]), freps.slice_move(s![idx, .., .., ..])));
+        }
+        actions
+    }
+
+    /// Update on a batch of transitions with a single backward pass, or,
+    /// with experience replay, add the transitions to the replay buffer
+    /// and update on a single mini-batch.
+    fn update_batch(
+        &mut self,
+        states: &[State],
+        _actions: &[Action],
+        rewards: &[f32],
+        next_states: &[State],
+    ) {
+        let transitions: Vec<Transition> = izip!(states, rewards, next_states)
+            .map(|(state, &reward, next_state)| Transition::new(state, reward, next_state))
+            .collect();
+        let err = if self.replay.is_some() {
+            self.replay_update(transitions)
+        } else {
+            let errs = self.backward_batch(&Batch::new(&transitions));
+            errs.scalar_sum() / errs.len() as f32
+        };
+        self.update_avg_reward(err);
+    }
+
+    fn load_weights(&mut self, path: &Path) -> io::Result<()> {
+        self.net.load(path)
+    }
+
+    fn save_weights(&self, path: &Path) -> io::Result<()> {
+        self.net.save(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use eventgen::Event;
+    use gridfuncs::{feature_rep, get_inuse_chs, BitGrid, Cell};
+    use std::env::temp_dir;
+    use structopt::StructOpt;
+    use vnet_agent::*;
+
+    #[test]
+    /// TDC(lambda) with lambda = 0 should equal the one-step update
+    fn test_lambda_zero() {
+        let mut net1 = VNet::new(&Opt::from_iter(&["DCA"]));
+        let mut net2 = VNet::new(&Opt::from_iter(&["DCA", "--lambda", "0"]));
+        let mut grid = BitGrid::default();
+        let mut frep = feature_rep(&grid);
+        for &(r, c, ch) in &[(0, 0, 4), (3, 2, 10), (6, 5, 4)] {
+            grid.set(r, c, ch, true);
+            let next_frep = feature_rep(&grid);
+            let err1 = net1.backward(&frep, 1.0, 0.5, 1.0, &next_frep);
+            let err2 = net2.backward(&frep, 1.0, 0.5, 1.0, &next_frep);
+            assert!((err1 - err2).abs() < 1e-6);
+            frep = next_frep;
+        }
+        // Equal up to floating point rounding
+        assert!(net1.weights.all_close(&net2.weights, 1e-9));
+        assert!(net1.grad_corr.all_close(&net2.grad_corr, 1e-9));
+    }
+
+    #[test]
+    /// A batch of one transition should give the one-step update
+    fn test_backward_batch() {
+        for rule in &["td0", "gtd2", "tdc", "tdcvariant"] {
+            let opt = Opt::from_iter(&["DCA", "--update_rule", rule]);
+            let (mut net1, mut net2) = (VNet::new(&opt), VNet::new(&opt));
+            let mut grid = BitGrid::default();
+            let mut frep = feature_rep(&grid);
+            for &(r, c, ch) in &[(0, 0, 4), (3, 2, 10), (6, 5, 4)] {
+                grid.set(r, c, ch, true);
+                let next_frep = feature_rep(&grid);
+                let err1 = net1.backward(&frep, 1.0, 0.5, 0.9, &next_frep);
+                let errs2 = net2.backward_batch(
+                    &frep.view().insert_axis(Axis(0)),
+                    &array![1.0],
+                    &array![0.5],
+                    &array![0.9],
+                    &next_frep.view().insert_axis(Axis(0)),
+                    &array![1.0],
+                );
+                assert!((err1 - errs2[[0]]).abs() < 1e-6);
+                frep = next_frep;
+            }
+            assert!(net1.weights.all_close(&net2.weights, 1e-9));
+            assert!(net1.grad_corr.all_close(&net2.grad_corr, 1e-9));
+        }
+    }
+
+    #[test]
+    /// The linear shortcut for the values of afterstates given as frep diffs should
+    /// equal the values of the afterstate freps
+    fn test_forward_diffs() {
+        let mut net = VNet::new(&Opt::from_iter(&["DCA"]));
+        for (i, w) in net.weights.iter_mut().enumerate() {
+            *w = (i % 7) as f32 * 0.01;
+        }
+        let mut grid = BitGrid::default();
+        for &(r, c, ch) in &[(0, 0, 4), (3, 2, 10), (3, 3, 4), (4, 2, 12)] {
+            grid.set(r, c, ch, true);
+        }
+        let frep = feature_rep(&grid);
+        let cell = Cell { row: 3, col: 2 };
+        for etype in &[EType::NEW, EType::END] {
+            let chs = match *etype {
+                EType::END => get_inuse_chs(&grid, &cell),
+                _ => get_eligible_chs(&grid, &cell),
+            };
+            let diffs = frep_diffs(&grid, &cell, etype, &chs);
+            let qvals1 = net.forward_diffs(&frep, &diffs);
+            let qvals2 = net.forward(&apply_diffs(&frep, &diffs));
+            assert!(qvals1.all_close(&qvals2, 1e-4));
+        }
+    }
+
+    #[test]
+    /// Eligibility traces are rejected for the update rules and training modes that
+    /// do not support them
+    fn test_lambda_conflicts() {
+        let ok = |args: &[&str]| Opt::from_iter(args).validate().is_ok();
+        assert!(ok(&["DCA", "--lambda", "0.8"]));
+        assert!(!ok(&["DCA", "--lambda", "0.8", "--update_rule", "GTD2"]));
+        assert!(!ok(&["DCA", "--lambda", "0.8", "--replay", "Uniform"]));
+        assert!(!ok(&["DCA", "--lambda", "0.8", "--n_envs", "2"]));
+    }
+
+    #[test]
+    /// The empty grid is valued at the optimistic initial value
+    fn test_optimistic_init() {
+        let args = ["DCA", "--optimistic_init", "50", "--gamma", "0.9"];
+        let mut net = VNet::new(&Opt::from_iter(&args));
+        let value = net.forward(&feature_rep(&BitGrid::default()))[[0]];
+        assert!((value - 50.0).abs() < 1e-3);
+    }
+
+    #[test]
+    /// Hand-off look-ahead on frep differences values each departure as the best of the
+    /// hand-off arrivals that may follow it, as evaluated on full afterstate freps
+    fn test_hla_diffs() {
+        let mut agent = AAVNet::<VNet>::new(&Opt::from_iter(&["DCA"]));
+        for (i, w) in agent.net.weights.iter_mut().enumerate() {
+            *w = (i % 11) as f32 * 0.01 - 0.05;
+        }
+        let mut grid = BitGrid::default();
+        for &(r, c, ch) in &[(3, 2, 4), (3, 2, 10), (3, 3, 12), (1, 2, 10), (5, 3, 7)] {
+            grid.set(r, c, ch, true);
+        }
+        let (cell, to_cell) = (Cell { row: 3, col: 2 }, Cell { row: 3, col: 3 });
+        let mut state = State {
+            frep: feature_rep(&grid),
+            grid,
+            dt: 0.0,
+            event: Event {
+                id: 0,
+                time: 0.0,
+                etype: EType::END,
+                cell: cell.clone(),
+                ch: Some(4),
+                to_cell: Some(to_cell.clone()),
+            },
+        };
+        let chs = get_inuse_chs(&grid, &cell);
+        let (qvals, _) = agent.get_qvals(&state, &chs);
+        let freps = incremental_freps(&grid, &state.frep, &cell, &EType::END, &chs);
+        for (i, &ch) in chs.iter().enumerate() {
+            let mut astate = grid;
+            astate.set(cell.row, cell.col, ch, false);
+            let echs = get_eligible_chs(&astate, &to_cell);
+            let frep = freps.subview(Axis(0), i);
+            let ha_freps = incremental_freps(&astate, &frep, &to_cell, &EType::HOFF, &echs);
+            let best = agent
+                .net
+                .forward(&ha_freps)
+                .fold(f32::MIN, |max, &elem| max.max(elem));
+            assert!((qvals[[i]] - best).abs() < 1e-4);
+        }
+        // The frep of the selected afterstate is that of the departure alone
+        let (action, frep) = agent.get_action(&mut state);
+        grid.set(cell.row, cell.col, action.unwrap(), false);
+        assert_eq!(frep, feature_rep(&grid));
+    }
+
+    #[test]
+    /// The value of the next state is discounted in the TD error
+    fn test_discount() {
+        let mut net = VNet::new(&Opt::from_iter(&["DCA", "--update_rule", "td0"]));
+        net.weights.fill(0.01);
+        let mut grid = BitGrid::default();
+        let frep = feature_rep(&grid);
+        grid.set(3, 2, 10, true);
+        let next_frep = feature_rep(&grid);
+        let (value, next_value) = (net.forward(&frep)[[0]], net.forward(&next_frep)[[0]]);
+        let td_err = net.backward(&frep, 1.0, 0.0, 0.5, &next_frep);
+        assert!((td_err - (1.0 + 0.5 * next_value - value)).abs() < 1e-4);
+    }
+
+    #[test]
+    /// Gradient correction weights and eligibility traces are saved and loaded along
+    /// with the weights when requested
+    fn test_save_load_traces() {
+        let opt = Opt::from_iter(&["DCA", "--lambda", "0.8", "--with_traces"]);
+        let mut net = VNet::new(&opt);
+        let mut grid = BitGrid::default();
+        let frep = feature_rep(&grid);
+        grid.set(3, 2, 10, true);
+        net.weights.fill(0.01);
+        net.backward(&frep, 1.0, 0.0, 1.0, &feature_rep(&grid));
+        let path = temp_dir().join("rustdca_test_vnet.npy");
+        net.save(&path).unwrap();
+        let mut net2 = VNet::new(&opt);
+        net2.load(&path).unwrap();
+        assert_eq!(net.weights, net2.weights);
+        assert_eq!(net.grad_corr, net2.grad_corr);
+        assert_eq!(net.traces, net2.traces);
     }
 }